@@ -0,0 +1,81 @@
+//! Compares the throughput of the locked and the lock-free ring
+//!
+//! Run with `cargo bench -p hashtable_shm`, every configuration moves `MESSAGES` requests
+//! from a number of producer threads to a number of consumer threads through one request ring.
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use hashtable_shm::shm_ipc::{Operation, QueueConfig, Request, RingKind, ShmQueue};
+
+const MESSAGES: usize = 200_000;
+const QUEUE_DEPTH: usize = 64;
+
+fn run(ring: RingKind, producers: usize, consumers: usize) -> Duration {
+    let name = format!("bench-ring-{}", std::process::id());
+    let config = QueueConfig {
+        request_capacity: QUEUE_DEPTH,
+        response_capacity: QUEUE_DEPTH,
+        ring,
+        ..Default::default()
+    };
+    let queue: ShmQueue<u64, u64> =
+        ShmQueue::with_config(&name, true, &config).expect("Failed to setup Queue");
+    let received = AtomicUsize::new(0);
+
+    let start = Instant::now();
+    thread::scope(|s| {
+        for producer in 0..producers {
+            let queue = &queue;
+            s.spawn(move || {
+                for counter in (producer..MESSAGES).step_by(producers) {
+                    let request = Request {
+                        operation: Operation::Insert.into(),
+                        priority: 0,
+                        key: counter as u64,
+                        val: counter as u64,
+                        counter,
+                    };
+                    queue
+                        .request_put_blocking(&request)
+                        .expect("Failed to put request");
+                }
+            });
+        }
+        for _ in 0..consumers {
+            s.spawn(|| {
+                while received.load(Ordering::Relaxed) < MESSAGES {
+                    if queue.request_get_timeout(Duration::from_millis(10)).is_ok() {
+                        received.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+
+    queue.stop().expect("Failed to unlink shared memory");
+    elapsed
+}
+
+fn main() {
+    println!(
+        "{:<10} {:>9} {:>9} {:>14}",
+        "ring", "producers", "consumers", "messages/s"
+    );
+    for (producers, consumers) in [(1, 1), (1, 4), (4, 1), (4, 4), (8, 8)] {
+        for ring in [RingKind::Locked, RingKind::LockFree] {
+            let elapsed = run(ring, producers, consumers);
+            println!(
+                "{:<10} {:>9} {:>9} {:>14.0}",
+                format!("{:?}", ring),
+                producers,
+                consumers,
+                MESSAGES as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
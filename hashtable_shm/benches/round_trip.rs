@@ -0,0 +1,76 @@
+//! Compares the round-trip latency of the locked and the lock-free ring
+//!
+//! Run with `cargo bench -p hashtable_shm`, a client thread sends one request at a time
+//! and waits for the response of an echo server thread before sending the next one.
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use hashtable_shm::shm_ipc::{
+    Operation, QueueConfig, Request, Response, RingKind, ShmQueue, Status,
+};
+
+const ROUND_TRIPS: usize = 100_000;
+
+fn run(ring: RingKind) -> Duration {
+    let name = format!("bench-round-trip-{}", std::process::id());
+    let config = QueueConfig {
+        ring,
+        ..Default::default()
+    };
+    let server: ShmQueue<u64, u64> =
+        ShmQueue::with_config(&name, true, &config).expect("Failed to setup Queue");
+    let client: ShmQueue<u64, u64> =
+        ShmQueue::new(&name, false).expect("Failed to connect to Queue");
+
+    let start = Instant::now();
+    thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..ROUND_TRIPS {
+                let request = server.request_get().expect("Failed to get request");
+                let response = Response {
+                    operation: request.operation,
+                    status: Status::Ok.into(),
+                    key: request.key,
+                    val: request.val,
+                    counter: request.counter,
+                };
+                server
+                    .response_put_blocking(&response)
+                    .expect("Failed to put response");
+            }
+        });
+
+        for counter in 0..ROUND_TRIPS {
+            let request = Request {
+                operation: Operation::Read.into(),
+                priority: 0,
+                key: counter as u64,
+                val: 0,
+                counter,
+            };
+            client
+                .request_put_blocking(&request)
+                .expect("Failed to put request");
+            client.response_get().expect("Failed to get response");
+        }
+    });
+    let elapsed = start.elapsed();
+
+    server.stop().expect("Failed to unlink shared memory");
+    elapsed
+}
+
+fn main() {
+    println!("{:<10} {:>16}", "ring", "round trip (ns)");
+    for ring in [RingKind::Locked, RingKind::LockFree] {
+        let elapsed = run(ring);
+        println!(
+            "{:<10} {:>16.0}",
+            format!("{:?}", ring),
+            elapsed.as_nanos() as f64 / ROUND_TRIPS as f64
+        );
+    }
+}
@@ -0,0 +1,927 @@
+use crate::shm_ipc::{
+    self, Blob, Operation, Request, Response, ShmQueue, Status, HEARTBEAT_INTERVAL,
+    HEARTBEAT_TIMEOUT,
+};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Queue between a client and `hashtable_shm_server`, keys and values are stored in the blob arena
+pub type Queue = ShmQueue<Blob, Blob>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Shared memory: {0}")]
+    Ipc(#[from] shm_ipc::Error),
+
+    #[error("Request failed: {0}")]
+    Failed(Status),
+
+    #[error("Lost the connection to the server: {0}")]
+    Disconnected(String),
+
+    #[error("Server did not answer in time")]
+    Timeout,
+
+    #[error("Queue has no eventfd")]
+    NoEventFd,
+}
+
+/// Default for `Client::set_timeout`
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `Client` waits for space in a full request ring before it takes stale responses again
+const STALE_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug)]
+/// Operation on the hash table, see `Client::execute`
+pub enum Command<'a> {
+    Read(&'a [u8]),
+    Insert(&'a [u8], &'a [u8]),
+    Delete(&'a [u8]),
+}
+
+/// Blocking client for the hash table
+///
+/// Every call waits for its responses, `execute` pipelines a whole batch of commands. Responses can arrive
+/// in any order and are matched to their request by `counter`. Responses to requests of an earlier call
+/// that failed are dropped. A background thread sends the heartbeats, so the server keeps the queue of an idle client.
+///
+/// The client only owns the queue, so the connection or registration it was obtained with has to be kept
+/// until the client is dropped.
+pub struct Client {
+    queue: Arc<Queue>,
+    next_counter: usize,
+    timeout: Duration,
+    priority: u32,
+    stopped: Arc<AtomicBool>,
+    heartbeat: Option<thread::JoinHandle<()>>,
+}
+
+/// Client for the hash table returning futures that resolve once the server answered
+///
+/// Requests are sent when their future is polled for the first time, so every future that is polled
+/// concurrently (e.g. with `join`) is pipelined. Responses can arrive in any order and are matched to their
+/// request by `counter`. The futures do not depend on a particular runtime.
+///
+/// The client only owns the queue, so the connection or registration it was obtained with has to be kept
+/// until the client is dropped. Requests still waiting for a response then fail with `Error::Disconnected`.
+pub struct AsyncClient {
+    shared: Arc<Shared>,
+    priority: u32,
+}
+
+/// State shared between the client, its futures and whoever takes the responses
+struct Shared {
+    queue: Queue,
+    state: Mutex<State>,
+    /// The client was dropped, so nobody takes responses anymore
+    stopped: AtomicBool,
+}
+
+#[derive(Default)]
+struct State {
+    next_counter: usize,
+    calls: HashMap<usize, Call>,
+    /// Number of requests without a response yet
+    outstanding: usize,
+    /// Futures waiting for space in the request ring or the arena, woken whenever responses arrived
+    blocked: Vec<Waker>,
+    /// Reason why no more responses are taken, every request fails with it afterwards
+    disconnected: Option<String>,
+}
+
+/// Request that was sent to the server
+#[derive(Default)]
+struct Call {
+    result: Option<Result<Vec<u8>, Error>>,
+    waker: Option<Waker>,
+    /// The future was dropped, so the response is discarded
+    abandoned: bool,
+}
+
+/// Future of a single request, see `AsyncClient`
+///
+/// Resolves to the value for reads and to an empty vector otherwise.
+pub struct ResponseFuture {
+    shared: Arc<Shared>,
+    operation: Operation,
+    key: Vec<u8>,
+    val: Option<Vec<u8>>,
+    priority: u32,
+    /// Counter of the request once it was sent
+    counter: Option<usize>,
+}
+
+impl AsyncClient {
+    /// Takes the responses in a background thread, which works with every executor
+    ///
+    /// The thread sends the heartbeats of the client and exits once the client was dropped or the server is gone.
+    pub fn new(queue: Queue) -> Self {
+        let shared = Shared::new(queue);
+        {
+            let shared = shared.clone();
+            thread::spawn(move || shared.run());
+        }
+        Self {
+            shared,
+            priority: 0,
+        }
+    }
+
+    /// Takes the responses in a task on the current tokio runtime, which waits on the eventfd of the queue
+    ///
+    /// Requires a queue handed over with `QueueConfig::event_fd` and panics outside of a runtime.
+    #[cfg(feature = "tokio")]
+    pub fn with_tokio(queue: Queue) -> Result<Self, Error> {
+        if queue.event_fd().is_none() {
+            return Err(Error::NoEventFd);
+        }
+        let shared = Shared::new(queue);
+        tokio::spawn(shared.clone().run_tokio());
+        Ok(Self {
+            shared,
+            priority: 0,
+        })
+    }
+
+    /// Sets the priority of requests created afterwards, see `Request::priority`
+    pub fn set_priority(&mut self, priority: u32) {
+        self.priority = priority;
+    }
+
+    /// Reads the value stored for `key`
+    pub fn read(
+        &self,
+        key: &[u8],
+    ) -> impl Future<Output = Result<Vec<u8>, Error>> + Send + 'static {
+        self.call(Operation::Read, key, None)
+    }
+
+    /// Inserts `value` for `key`, fails with `Status::KeyExists` if there already is one
+    pub fn insert(
+        &self,
+        key: &[u8],
+        value: &[u8],
+    ) -> impl Future<Output = Result<(), Error>> + Send + 'static {
+        let call = self.call(Operation::Insert, key, Some(value));
+        async move { call.await.map(drop) }
+    }
+
+    /// Deletes `key`, fails with `Status::KeyMissing` if it does not exist
+    pub fn delete(&self, key: &[u8]) -> impl Future<Output = Result<(), Error>> + Send + 'static {
+        let call = self.call(Operation::Delete, key, None);
+        async move { call.await.map(drop) }
+    }
+
+    fn call(&self, operation: Operation, key: &[u8], val: Option<&[u8]>) -> ResponseFuture {
+        ResponseFuture {
+            shared: self.shared.clone(),
+            operation,
+            key: key.to_vec(),
+            val: val.map(<[u8]>::to_vec),
+            priority: self.priority,
+            counter: None,
+        }
+    }
+}
+
+impl Command<'_> {
+    fn parts(&self) -> (Operation, &[u8], Option<&[u8]>) {
+        match *self {
+            Command::Read(key) => (Operation::Read, key, None),
+            Command::Insert(key, value) => (Operation::Insert, key, Some(value)),
+            Command::Delete(key) => (Operation::Delete, key, None),
+        }
+    }
+}
+
+impl Client {
+    pub fn new(queue: Queue) -> Self {
+        let queue = Arc::new(queue);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let heartbeat = {
+            let queue = queue.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    queue.heartbeat();
+                    thread::park_timeout(HEARTBEAT_INTERVAL);
+                }
+            })
+        };
+
+        Self {
+            queue,
+            next_counter: 0,
+            timeout: DEFAULT_TIMEOUT,
+            priority: 0,
+            stopped,
+            heartbeat: Some(heartbeat),
+        }
+    }
+
+    /// Sets how long a call waits for the server to make progress before it fails with `Error::Timeout`
+    ///
+    /// A server that died or stopped sending heartbeats is detected after `HEARTBEAT_TIMEOUT` at the latest.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets the priority of the requests sent afterwards, see `Request::priority`
+    ///
+    /// Requests of a higher priority overtake queued ones of a lower priority, so there is no order between them.
+    pub fn set_priority(&mut self, priority: u32) {
+        self.priority = priority;
+    }
+
+    /// Reads the value stored for `key`
+    pub fn read(&mut self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        self.single(Command::Read(key))
+    }
+
+    /// Inserts `value` for `key`, fails with `Status::KeyExists` if there already is one
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.single(Command::Insert(key, value)).map(drop)
+    }
+
+    /// Deletes `key`, fails with `Status::KeyMissing` if it does not exist
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.single(Command::Delete(key)).map(drop)
+    }
+
+    fn single(&mut self, command: Command<'_>) -> Result<Vec<u8>, Error> {
+        self.execute(&[command])?
+            .pop()
+            .expect("Every command has an outcome")
+    }
+
+    /// Sends `commands` pipelined, as many at once as fit into the queue, and waits for all responses
+    ///
+    /// returns the outcome of every command in order: the value for reads and an empty vector otherwise.
+    /// Only fails as a whole if the server is gone or did not make progress in time.
+    pub fn execute(
+        &mut self,
+        commands: &[Command<'_>],
+    ) -> Result<Vec<Result<Vec<u8>, Error>>, Error> {
+        let mut outcomes: Vec<Option<Result<Vec<u8>, Error>>> =
+            commands.iter().map(|_| None).collect();
+        // Index of the command of each request waiting for its response
+        let mut pending: HashMap<usize, usize> = HashMap::new();
+        let mut next = 0;
+        let mut last_progress = Instant::now();
+
+        while next < commands.len() || !pending.is_empty() {
+            while let Some(command) = commands.get(next) {
+                let (operation, key, val) = command.parts();
+                let counter = self.next_counter;
+                // Without requests of our own in flight, only the server taking those of an earlier call frees space
+                let wait = pending.is_empty().then_some(STALE_INTERVAL);
+                match send(
+                    &self.queue,
+                    operation,
+                    key,
+                    val,
+                    counter,
+                    self.priority,
+                    wait,
+                ) {
+                    Ok(()) => {
+                        pending.insert(self.next_counter, next);
+                        self.next_counter = self.next_counter.wrapping_add(1);
+                    }
+                    // Space is released once the server answered one of our requests
+                    Err(shm_ipc::Error::BufferFull | shm_ipc::Error::ArenaFull)
+                        if !pending.is_empty() =>
+                    {
+                        break
+                    }
+                    // The server might wait for space for its responses to an earlier call
+                    Err(shm_ipc::Error::BufferFull) => {
+                        if self.discard_stale()? > 0 {
+                            last_progress = Instant::now();
+                        } else {
+                            self.queue.check_peer(HEARTBEAT_TIMEOUT)?;
+                            if last_progress.elapsed() >= self.timeout {
+                                return Err(Error::Timeout);
+                            }
+                        }
+                        continue;
+                    }
+                    Err(shm_ipc::Error::ArenaFull) => {
+                        outcomes[next] = Some(Err(Error::Ipc(shm_ipc::Error::ArenaFull)))
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                next += 1;
+            }
+            if pending.is_empty() {
+                continue;
+            }
+
+            let remaining = self.timeout.saturating_sub(last_progress.elapsed());
+            match self
+                .queue
+                .response_get_many_timeout(pending.len(), remaining.min(HEARTBEAT_INTERVAL))
+            {
+                Ok(responses) => {
+                    last_progress = Instant::now();
+                    for response in responses {
+                        let outcome = outcome(&self.queue, &response);
+                        if let Some(index) = pending.remove(&response.counter) {
+                            outcomes[index] = Some(outcome);
+                        }
+                    }
+                }
+                Err(shm_ipc::Error::OwnerDied) => (), // The ring was recovered, so just try again
+                Err(shm_ipc::Error::Timeout) => {
+                    self.queue.check_peer(HEARTBEAT_TIMEOUT)?;
+                    if last_progress.elapsed() >= self.timeout {
+                        return Err(Error::Timeout);
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(outcomes
+            .into_iter()
+            .map(|outcome| outcome.expect("Every command has an outcome"))
+            .collect())
+    }
+
+    /// Takes the responses to requests of an earlier call that failed, nobody waits for them anymore
+    ///
+    /// returns the number of responses taken
+    fn discard_stale(&self) -> Result<usize, Error> {
+        match self
+            .queue
+            .response_try_get_many(self.queue.response_capacity())
+        {
+            Ok(responses) => {
+                for response in &responses {
+                    let _ = outcome(&self.queue, response);
+                }
+                Ok(responses.len())
+            }
+            Err(shm_ipc::Error::BufferEmpty | shm_ipc::Error::OwnerDied) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.thread().unpark();
+            let _ = heartbeat.join();
+        }
+    }
+}
+
+impl Drop for AsyncClient {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Shared {
+    fn new(queue: Queue) -> Arc<Self> {
+        Arc::new(Self {
+            queue,
+            state: Mutex::new(State::default()),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Nothing panics while holding the lock, so the state is consistent anyway
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sends a request and registers the call waiting for its response
+    ///
+    /// returns the counter of the request
+    fn send(
+        &self,
+        state: &mut State,
+        operation: Operation,
+        key: &[u8],
+        val: Option<&[u8]>,
+        priority: u32,
+    ) -> Result<usize, shm_ipc::Error> {
+        let counter = state.next_counter;
+        send(&self.queue, operation, key, val, counter, priority, None)?;
+
+        state.next_counter = counter.wrapping_add(1);
+        state.outstanding += 1;
+        state.calls.insert(counter, Call::default());
+        Ok(counter)
+    }
+
+    /// Hands `responses` to the futures waiting for them
+    fn dispatch(&self, responses: Vec<Response<Blob, Blob>>) {
+        let mut state = self.lock();
+        for response in responses {
+            let result = outcome(&self.queue, &response);
+
+            // Responses to requests we do not know are dropped
+            let Entry::Occupied(mut entry) = state.calls.entry(response.counter) else {
+                continue;
+            };
+            if entry.get().abandoned {
+                entry.remove();
+            } else {
+                let call = entry.get_mut();
+                call.result = Some(result);
+                if let Some(waker) = call.waker.take() {
+                    waker.wake();
+                }
+            }
+            state.outstanding = state.outstanding.saturating_sub(1);
+        }
+
+        for waker in state.blocked.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Fails every request waiting for a response and every later one
+    fn disconnect(&self, reason: String) {
+        let mut state = self.lock();
+        state.calls.retain(|_, call| !call.abandoned);
+        for call in state.calls.values_mut() {
+            if call.result.is_none() {
+                call.result = Some(Err(Error::Disconnected(reason.clone())));
+                if let Some(waker) = call.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+        state.outstanding = 0;
+        for waker in state.blocked.drain(..) {
+            waker.wake();
+        }
+        state.disconnected = Some(reason);
+    }
+
+    /// Takes responses until the client was dropped or the server is gone
+    fn run(&self) {
+        let max = self.queue.response_capacity();
+        while !self.stopped.load(Ordering::Relaxed) {
+            self.queue.heartbeat();
+            match self
+                .queue
+                .response_get_many_timeout(max, HEARTBEAT_INTERVAL)
+            {
+                Ok(responses) => self.dispatch(responses),
+                Err(shm_ipc::Error::OwnerDied) => (), // The ring was recovered, so just try again
+                Err(shm_ipc::Error::Timeout) => {
+                    if let Err(e) = self.queue.check_peer(HEARTBEAT_TIMEOUT) {
+                        return self.disconnect(e.to_string());
+                    }
+                }
+                Err(e) => return self.disconnect(e.to_string()),
+            }
+        }
+        self.disconnect("client was dropped".to_string());
+    }
+
+    /// Same as `run`, but waits for responses on the eventfd of the queue
+    #[cfg(feature = "tokio")]
+    async fn run_tokio(self: Arc<Self>) {
+        use tokio::io::unix::AsyncFd;
+        use tokio::io::Interest;
+
+        let event = match AsyncFd::with_interest(EventFd(self.clone()), Interest::READABLE) {
+            Ok(event) => event,
+            Err(e) => return self.disconnect(e.to_string()),
+        };
+        let max = self.queue.response_capacity();
+        while !self.stopped.load(Ordering::Relaxed) {
+            self.queue.heartbeat();
+            // Cleared first, so responses put after we looked at the ring signal the eventfd again
+            if let Err(e) = self.queue.clear_event() {
+                return self.disconnect(e.to_string());
+            }
+            match self.queue.response_try_get_many(max) {
+                Ok(responses) => {
+                    self.dispatch(responses);
+                    continue;
+                }
+                Err(shm_ipc::Error::BufferEmpty) => (),
+                Err(shm_ipc::Error::OwnerDied) => continue,
+                Err(e) => return self.disconnect(e.to_string()),
+            }
+
+            match tokio::time::timeout(HEARTBEAT_INTERVAL, event.readable()).await {
+                Ok(Ok(mut guard)) => guard.clear_ready(),
+                Ok(Err(e)) => return self.disconnect(e.to_string()),
+                Err(_) => {
+                    if let Err(e) = self.queue.check_peer(HEARTBEAT_TIMEOUT) {
+                        return self.disconnect(e.to_string());
+                    }
+                }
+            }
+        }
+        self.disconnect("client was dropped".to_string());
+    }
+}
+
+/// Registers the eventfd of the queue with tokio, which needs an owner that lives as long as the registration
+#[cfg(feature = "tokio")]
+struct EventFd(Arc<Shared>);
+
+#[cfg(feature = "tokio")]
+impl std::os::fd::AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        // Checked by `AsyncClient::with_tokio`
+        self.0
+            .queue
+            .event_fd()
+            .map_or(-1, |event| event.as_raw_fd())
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<Vec<u8>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.shared.lock();
+
+        let counter = match this.counter {
+            Some(counter) => counter,
+            None => {
+                if let Some(reason) = &state.disconnected {
+                    return Poll::Ready(Err(Error::Disconnected(reason.clone())));
+                }
+                let val = this.val.as_deref();
+                let priority = this.priority;
+                match this
+                    .shared
+                    .send(&mut state, this.operation, &this.key, val, priority)
+                {
+                    Ok(counter) => {
+                        this.counter = Some(counter);
+                        counter
+                    }
+                    // Space is released once the server answered one of our requests
+                    Err(shm_ipc::Error::BufferFull | shm_ipc::Error::ArenaFull)
+                        if state.outstanding > 0 =>
+                    {
+                        state.blocked.push(cx.waker().clone());
+                        return Poll::Pending;
+                    }
+                    Err(e) => return Poll::Ready(Err(e.into())),
+                }
+            }
+        };
+
+        let call = state
+            .calls
+            .get_mut(&counter)
+            .expect("Call of a pending request is missing");
+        match call.result.take() {
+            Some(result) => {
+                state.calls.remove(&counter);
+                Poll::Ready(result)
+            }
+            None => {
+                call.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        let Some(counter) = self.counter else {
+            return;
+        };
+        let mut state = self.shared.lock();
+        if let Entry::Occupied(mut entry) = state.calls.entry(counter) {
+            match entry.get().result.is_some() {
+                true => {
+                    entry.remove();
+                }
+                false => entry.get_mut().abandoned = true,
+            }
+        }
+    }
+}
+
+/// Stores the payloads in the arena and puts the request into the ring
+///
+/// Waits at most `wait` for space in the ring, nothing is kept in the arena if the request could not be sent.
+fn send(
+    queue: &Queue,
+    operation: Operation,
+    key: &[u8],
+    val: Option<&[u8]>,
+    counter: usize,
+    priority: u32,
+    wait: Option<Duration>,
+) -> Result<(), shm_ipc::Error> {
+    let key = alloc(queue, key)?;
+    let val = match val {
+        Some(val) => alloc(queue, val).inspect_err(|_| {
+            let _ = queue.blob_free(&key);
+        })?,
+        None => Blob::default(),
+    };
+
+    let request = Request::new(operation, key, val, counter).with_priority(priority);
+    loop {
+        let res = match wait {
+            Some(timeout) => queue.request_put_timeout(&request, timeout),
+            None => queue.request_put(&request),
+        };
+        match res {
+            Err(shm_ipc::Error::OwnerDied) => (), // The ring was recovered, so just try again
+            Err(e) => {
+                let _ = queue.blob_free(&key);
+                let _ = queue.blob_free(&val);
+                return Err(e);
+            }
+            Ok(()) => return Ok(()),
+        }
+    }
+}
+
+fn alloc(queue: &Queue, data: &[u8]) -> Result<Blob, shm_ipc::Error> {
+    loop {
+        match queue.blob_alloc(data) {
+            Err(shm_ipc::Error::OwnerDied) => (), // The arena was recovered, so just try again
+            res => return res,
+        }
+    }
+}
+
+/// Releases the payloads of `response` and turns it into the outcome of its request
+fn outcome(queue: &Queue, response: &Response<Blob, Blob>) -> Result<Vec<u8>, Error> {
+    let _ = queue.blob_free(&response.key);
+    let result = match response.status() {
+        Ok(Status::Ok) => return queue.blob_take(&response.val).map_err(Error::from),
+        Ok(status) => Err(Error::Failed(status)),
+        Err(e) => Err(e.into()),
+    };
+    let _ = queue.blob_free(&response.val);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shm_ipc::QueueConfig;
+    use std::os::unix::net::UnixStream;
+    use std::task::Wake;
+    use std::time::Duration;
+
+    /// Queue pair as handed out by a server listening on a socket
+    fn connect() -> (Arc<Queue>, Queue) {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let config = QueueConfig {
+            request_capacity: 4,
+            response_capacity: 4,
+            event_fd: true,
+            ..Default::default()
+        };
+        let ipc_server = Queue::anonymous(&config).expect("Failed to setup Queue");
+        ipc_server.send_to(&server_stream).unwrap();
+        let ipc_client = Queue::receive_from(&client_stream).expect("Failed to receive Queue");
+        (Arc::new(ipc_server), ipc_client)
+    }
+
+    /// Answers requests on `ipc` until it is closed, each batch in reverse order
+    fn serve(ipc: Arc<Queue>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut table: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+            while let Ok(requests) = ipc.request_get_many(3) {
+                let responses: Vec<_> = requests
+                    .iter()
+                    .rev()
+                    .map(|request| {
+                        let key = ipc.blob_take(&request.key).unwrap();
+                        let val = ipc.blob_take(&request.val).unwrap();
+                        let operation = request.operation().unwrap();
+                        let (status, val) = match operation {
+                            Operation::Read => match table.get(&key) {
+                                Some(val) => (Status::Ok, ipc.blob_alloc(val).unwrap()),
+                                None => (Status::KeyMissing, Blob::default()),
+                            },
+                            Operation::Insert => match table.entry(key) {
+                                Entry::Occupied(_) => (Status::KeyExists, Blob::default()),
+                                Entry::Vacant(entry) => {
+                                    entry.insert(val);
+                                    (Status::Ok, Blob::default())
+                                }
+                            },
+                            Operation::Delete => match table.remove(&key) {
+                                Some(_) => (Status::Ok, Blob::default()),
+                                None => (Status::KeyMissing, Blob::default()),
+                            },
+                        };
+                        Response::new(operation, status, Blob::default(), val, request.counter)
+                    })
+                    .collect();
+
+                let mut sent = 0;
+                while sent < responses.len() {
+                    match ipc.response_put_many_blocking(&responses[sent..]) {
+                        Ok(count) => sent += count,
+                        Err(_) => return,
+                    }
+                }
+            }
+        })
+    }
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Polls all `futures` concurrently on the current thread until every one of them finished
+    fn join_all<F: Future>(futures: Vec<F>) -> Vec<F::Output> {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut futures: Vec<_> = futures.into_iter().map(Box::pin).collect();
+        let mut outputs: Vec<_> = futures.iter().map(|_| None).collect();
+
+        while outputs.iter().any(Option::is_none) {
+            for (future, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+                if output.is_none() {
+                    if let Poll::Ready(res) = future.as_mut().poll(&mut cx) {
+                        *output = Some(res);
+                    }
+                }
+            }
+            thread::park_timeout(Duration::from_millis(100));
+        }
+        outputs.into_iter().map(Option::unwrap).collect()
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        join_all(vec![future]).pop().unwrap()
+    }
+
+    #[test]
+    fn pipelined() {
+        let (ipc_server, ipc_client) = connect();
+        let server = serve(ipc_server.clone());
+        let client = AsyncClient::new(ipc_client);
+        let keys: Vec<Vec<u8>> = (0..20).map(|i| format!("key{i}").into_bytes()).collect();
+
+        // More requests than fit into the ring at once
+        let inserts = keys.iter().map(|key| client.insert(key, key)).collect();
+        assert!(join_all(inserts).iter().all(Result::is_ok));
+        let reads = keys.iter().map(|key| client.read(key)).collect();
+        for (key, res) in keys.iter().zip(join_all(reads)) {
+            assert_eq!(&res.unwrap(), key);
+        }
+
+        assert!(matches!(
+            block_on(client.insert(b"key0", b"other")),
+            Err(Error::Failed(Status::KeyExists))
+        ));
+        block_on(client.delete(b"key0")).unwrap();
+        assert!(matches!(
+            block_on(client.read(b"key0")),
+            Err(Error::Failed(Status::KeyMissing))
+        ));
+
+        // A dropped future does not disturb the others
+        let mut abandoned = Box::pin(client.read(b"key1"));
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let _ = abandoned.as_mut().poll(&mut Context::from_waker(&waker));
+        drop(abandoned);
+        assert_eq!(block_on(client.read(b"key2")).unwrap(), b"key2");
+
+        ipc_server.close().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn blocking() {
+        let (ipc_server, ipc_client) = connect();
+        let server = serve(ipc_server.clone());
+        let mut client = Client::new(ipc_client);
+
+        client.insert(b"key", b"val").unwrap();
+        assert_eq!(client.read(b"key").unwrap(), b"val");
+        assert!(matches!(
+            client.insert(b"key", b"other"),
+            Err(Error::Failed(Status::KeyExists))
+        ));
+        client.delete(b"key").unwrap();
+        assert!(matches!(
+            client.delete(b"key"),
+            Err(Error::Failed(Status::KeyMissing))
+        ));
+
+        // More commands than fit into the ring at once, the outcomes are in the order of the commands
+        let keys: Vec<Vec<u8>> = (0..20).map(|i| format!("key{i}").into_bytes()).collect();
+        let mut commands: Vec<_> = keys.iter().map(|key| Command::Insert(key, key)).collect();
+        commands.extend(keys.iter().map(|key| Command::Read(key)));
+        commands.push(Command::Read(b"missing"));
+        let outcomes = client.execute(&commands).unwrap();
+        assert_eq!(outcomes.len(), commands.len());
+        for (key, outcome) in keys.iter().zip(&outcomes[keys.len()..]) {
+            assert_eq!(outcome.as_ref().unwrap(), key);
+        }
+        assert!(matches!(
+            outcomes.last(),
+            Some(Err(Error::Failed(Status::KeyMissing)))
+        ));
+
+        ipc_server.close().unwrap();
+        server.join().unwrap();
+        assert!(client.read(b"key0").is_err());
+
+        // Nobody answers
+        let (ipc_server, ipc_client) = connect();
+        let mut client = Client::new(ipc_client);
+        client.set_timeout(Duration::from_millis(50));
+        assert!(matches!(client.read(b"key"), Err(Error::Timeout)));
+
+        // The requests of failed calls fill the ring until the server shows up again
+        let stale = [Command::Read(b"key"); 3];
+        assert!(matches!(client.execute(&stale), Err(Error::Timeout)));
+        client.set_timeout(DEFAULT_TIMEOUT);
+        let server = {
+            let ipc_server = ipc_server.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                serve(ipc_server).join().unwrap();
+            })
+        };
+        assert!(matches!(
+            client.read(b"key"),
+            Err(Error::Failed(Status::KeyMissing))
+        ));
+        ipc_server.close().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn disconnected() {
+        let (ipc_server, ipc_client) = connect();
+        let client = AsyncClient::new(ipc_client);
+
+        // Nobody answers, the request fails once the server closed the queue
+        let pending = client.read(b"key");
+        let closer = {
+            let ipc_server = ipc_server.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                ipc_server.close().unwrap();
+            })
+        };
+        assert!(matches!(block_on(pending), Err(Error::Disconnected(_))));
+        closer.join().unwrap();
+        assert!(matches!(
+            block_on(client.insert(b"key", b"val")),
+            Err(Error::Disconnected(_))
+        ));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn tokio() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (ipc_server, ipc_client) = connect();
+        let server = serve(ipc_server.clone());
+
+        runtime.block_on(async {
+            let client = AsyncClient::with_tokio(ipc_client).unwrap();
+            let tasks: Vec<_> = (0..10)
+                .map(|i| tokio::spawn(client.insert(&[i], &[i * 2])))
+                .collect();
+            for task in tasks {
+                task.await.unwrap().unwrap();
+            }
+            for i in 0..10 {
+                assert_eq!(client.read(&[i]).await.unwrap(), [i * 2]);
+            }
+        });
+
+        ipc_server.close().unwrap();
+        server.join().unwrap();
+    }
+}
@@ -1,2 +1,7 @@
+// Allows the ShmSafe derive to refer to this crate by name from within
+extern crate self as hashtable_shm;
+
+pub mod client;
 pub mod hashtable;
 pub mod shm_ipc;
+pub mod shm_safe;
@@ -1,12 +1,36 @@
+use crate::hashtable;
+use crate::shm_safe::ShmSafe;
 use libc::PTHREAD_PROCESS_SHARED;
-use rustix::fs::{ftruncate, Mode};
-use rustix::mm::{mmap, MapFlags, ProtFlags};
+use rustix::event::{eventfd, EventfdFlags};
+use rustix::fd::{AsFd, BorrowedFd, OwnedFd};
+use rustix::fs::{
+    fchmod, fchown, fcntl_add_seals, fstat, ftruncate, memfd_create, Gid, MemfdFlags, Mode,
+    SealFlags,
+};
+use rustix::io::{pread, read, write};
+use rustix::mm::{mmap, munmap, MapFlags, ProtFlags};
 use rustix::shm;
+use std::fmt;
 use std::mem::size_of;
-use std::mem::MaybeUninit;
-use std::ptr::null_mut;
+use std::mem::{align_of, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::net::UnixStream;
+use std::ptr::{self, null_mut};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
 use thiserror::Error;
 
+mod control;
+mod futex;
+mod lock_free;
+mod process;
+mod socket;
+
+pub use control::{Control, ControlEvent, Registration};
+use futex::{AdaptiveSpin, Notifier};
+use process::Process;
+pub use socket::{peer_credentials, PeerCredentials};
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("RustixIo: {0}")]
@@ -15,321 +39,3479 @@ pub enum Error {
     #[error("Mutex init: {0}")]
     MutexInit(String),
 
-    #[error("Cond init: {0}")]
-    CondInit(String),
-
     #[error("Buffer is full")]
     BufferFull,
+
+    #[error("Buffer is empty")]
+    BufferEmpty,
+
+    #[error("Timed out waiting for data")]
+    Timeout,
+
+    #[error("Queue was closed")]
+    Closed,
+
+    #[error("Previous lock owner died, state was recovered")]
+    OwnerDied,
+
+    #[error("Unknown ring kind {0}")]
+    UnknownRingKind(u32),
+
+    #[error("Invalid operation {0}")]
+    InvalidOperation(u32),
+
+    #[error("Invalid status {0}")]
+    InvalidStatus(u32),
+
+    #[error("Queue capacity must be between 1 and {}", u32::MAX)]
+    InvalidCapacity,
+
+    #[error("Number of request lanes must be between 1 and {MAX_LANES}")]
+    InvalidLanes,
+
+    #[error("Blob arena is full")]
+    ArenaFull,
+
+    #[error("Blob is out of bounds: offset {offset}, len {len}")]
+    InvalidBlob { offset: usize, len: usize },
+
+    #[error("All client slots are in use")]
+    NoFreeSlot,
+
+    #[error("Unknown client slot {0}")]
+    UnknownSlot(usize),
+
+    #[error("Server rejected the client")]
+    Rejected,
+
+    #[error("Invalid prefix {0:?}, it must not be empty or contain slashes")]
+    InvalidPrefix(String),
+
+    #[error("Peer process {0} died")]
+    PeerDied(u32),
+
+    #[error("Peer process {0} stopped sending heartbeats")]
+    PeerTimeout(u32),
+
+    #[error("Segment is still owned by running process {0}")]
+    SegmentInUse(u32),
+
+    #[error("Segment exists, but does not record its owner")]
+    UnknownOwner,
+
+    #[error("Shared memory layout mismatch: {field} is {found}, expected {expected}")]
+    LayoutMismatch {
+        field: &'static str,
+        expected: u64,
+        found: u64,
+    },
 }
 
-pub struct ShmQueue<K: Clone, V: Clone> {
+/// Queue pair between one client and the server
+///
+/// Dropping the queue unmaps the shared memory. On the server side it also destroys the locks
+/// and unlinks the name, so the client must not use its queue after the server dropped its side.
+pub struct ShmQueue<K: ShmSafe, V: ShmSafe> {
     buffer: SharedBuffer<K, V>,
     server: bool,
-    name: String,
+    /// `None` for anonymous segments, see `ShmQueue::anonymous`
+    name: Option<String>,
+    fd: OwnedFd,
+    /// Inode of the segment, to tell if `name` still refers to it
+    inode: u64,
+    unlinked: AtomicBool,
+    /// Signals new responses, see `ShmQueue::event_fd`
+    event: Option<OwnedFd>,
+}
+
+/// Prefix of the names of the control segment and the queues, see `Control`
+pub const DEFAULT_PREFIX: &str = "hashtable";
+
+/// How often each side should call `ShmQueue::heartbeat`
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a side may go without a heartbeat before `ShmQueue::check_peer` gives up on it
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default number of entries that can be queued in each direction
+pub const DEFAULT_QUEUE_DEPTH: usize = 10;
+
+/// Upper bound for `QueueConfig::lanes`
+pub const MAX_LANES: usize = 8;
+
+/// How often a lane with requests may be passed over for higher lanes before it is served first
+const STARVATION_LIMIT: u32 = 4;
+
+/// Identifies a segment created by `ShmQueue` ("HTSHMQ" followed by two zero bytes)
+const MAGIC: u64 = u64::from_be_bytes(*b"HTSHMQ\0\0");
+/// Has to be increased on every change to the layout of the shared memory
+const PROTOCOL_VERSION: u32 = 16;
+/// First protocol version that records the owner of the segment
+const OWNER_VERSION: u32 = 6;
+
+/// Granularity of allocations in the blob arena
+const ARENA_CHUNK_SIZE: usize = 64;
+/// Number of chunks in the blob arena (256 KiB in total)
+const ARENA_CHUNKS: usize = 4096;
+
+#[derive(Clone, Debug)]
+/// Settings for a new queue
+///
+/// Only used by the server, clients pick up the values from the segment header.
+pub struct QueueConfig {
+    /// Number of requests that can be queued at once in each lane
+    pub request_capacity: usize,
+    /// Number of priority lanes for requests, see `Request::priority`
+    pub lanes: usize,
+    /// Number of responses that can be queued at once
+    pub response_capacity: usize,
+    /// Implementation used for both rings
+    pub ring: RingKind,
+    /// Take over an existing segment of the same name even if its owner is still running
+    pub force: bool,
+    /// Permissions of named segments, the client needs read and write access
+    pub mode: u32,
+    /// Group owning named segments, so clients of other users in that group can connect
+    pub group: Option<u32>,
+    /// Hand the client an eventfd that signals new responses, see `ShmQueue::event_fd`
+    ///
+    /// Only used for anonymous queues, as the eventfd is sent along with the memory by `ShmQueue::send_to`.
+    pub event_fd: bool,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            request_capacity: DEFAULT_QUEUE_DEPTH,
+            lanes: 1,
+            response_capacity: DEFAULT_QUEUE_DEPTH,
+            ring: RingKind::default(),
+            force: false,
+            mode: 0o600,
+            group: None,
+            event_fd: false,
+        }
+    }
 }
 
-const BUFFER_SIZE: usize = 10;
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+/// Implementation of the request and response rings
+pub enum RingKind {
+    /// Ring protected by a robust process-shared mutex, waiting on a futex
+    #[default]
+    Locked,
+    /// Lock-free ring using per-slot sequence numbers, which scales better with many threads on one ring
+    LockFree,
+}
 
 /// Abstraction of the buffer in shared memory
 ///
 /// Implements functions to mutate the data in the buffer synchronized via locks.
 /// Ideally we would try to use UnsafeCell instead of a mutable reference if possible.
-struct SharedBuffer<K: Clone, V: Clone>(*mut SharedBufferInner<K, V>);
+struct SharedBuffer<K: ShmSafe, V: ShmSafe> {
+    size: usize,
+    header: *mut SegmentHeader,
+    /// One ring per priority lane, the lowest priority first
+    request_buffers: Vec<Ring<Request<K, V>>>,
+    /// Number of times each lane was passed over while it had requests, only tracked in this process
+    skipped: Vec<AtomicU32>,
+    spin: AdaptiveSpin,
+    response_buffer: Ring<Response<K, V>>,
+    arena: *mut Arena,
+}
+
+/// Handle to a ring of the kind chosen by the server
+enum Ring<T> {
+    Locked(RingBuffer<T>),
+    LockFree(lock_free::RingBuffer<T>),
+}
+
+/// Offsets of the parts of a segment
+///
+/// The segment starts with the `SegmentHeader`, followed by the request rings, the response ring and the arena.
+/// Each ring consists of a `RingBufferInner` directly followed by its slots.
+struct Layout {
+    ring: RingKind,
+    request_capacity: u32,
+    response_capacity: u32,
+    lanes: u32,
+    request_offset: usize,
+    /// Distance between the request rings of two lanes
+    request_stride: usize,
+    response_offset: usize,
+    arena_offset: usize,
+    size: usize,
+}
+
+#[derive(Clone, Copy)]
+/// How long an operation on a ring waits for the ring to change
+enum Wait {
+    /// Return immediately
+    None,
+    /// Block until the ring changed
+    Forever,
+    /// Block until the deadline on `CLOCK_MONOTONIC` passed
+    Until(libc::timespec),
+}
 
 #[repr(C)]
-/// Representation of buffer allocated in shared memory
-struct SharedBufferInner<K: Clone, V: Clone> {
-    pub request_buffer: RingBuffer<Request<K, V>>,
-    pub response_buffer: RingBuffer<Response<K, V>>,
+/// Description of the layout at the start of the segment
+///
+/// The server writes it during setup and the client validates it before touching anything else,
+/// so builds with different key/value types or constants do not corrupt each others memory.
+/// `magic` is written last, so a client never sees a partially initialized header as valid.
+struct SegmentHeader {
+    owner: SegmentOwner,
+    key_size: u32,
+    key_align: u32,
+    value_size: u32,
+    value_align: u32,
+    ring_kind: u32,
+    request_capacity: u32,
+    response_capacity: u32,
+    segment_size: u64,
+    server: Heartbeat,
+    client: Heartbeat,
+    lanes: u32,
+    /// Wakes up readers waiting on any of the request lanes, only used with more than one lane
+    requests_changed: Notifier,
 }
 
 #[repr(C)]
-/// Ring buffer structure with locking
+/// Start of every segment, both of the queues and of the control segment
+///
+/// `version` and the owner are written first and keep their offsets in future versions,
+/// so a server can tell whether a segment left behind by another build is stale.
+/// It is the smallest part every segment has, so even a small segment can be reclaimed.
+struct SegmentOwner {
+    magic: AtomicU64,
+    version: u32,
+    pid: u32,
+    start_time: u64,
+}
+
+#[repr(C)]
+/// Liveness record of one side of a queue
+///
+/// `pid` is written last, so a reader never pairs it with the start time of a previous process.
+struct Heartbeat {
+    pid: AtomicU32,
+    start_time: AtomicU64,
+    /// Milliseconds on `CLOCK_MONOTONIC`, which is the same clock in every process
+    beat: AtomicU64,
+}
+
+/// Handle to a ring buffer in shared memory
+///
+/// The ring has one more slot than its capacity, so that a full buffer can be told apart from an empty one.
 struct RingBuffer<T> {
+    inner: *mut RingBufferInner,
+    slots: *mut T,
+    len: usize,
+    spin: AdaptiveSpin,
+}
+
+#[repr(C)]
+/// Ring buffer structure with locking
+///
+/// Followed directly by the slots of the ring in the shared memory.
+/// Waiters sleep on a futex outside of the lock, see `Notifier`, so a put or get only makes
+/// the wake syscall if somebody actually waits.
+struct RingBufferInner {
     lock: libc::pthread_mutex_t,
-    has_data: libc::pthread_cond_t,
-    buffer: [T; BUFFER_SIZE],
+    has_data: Notifier,
+    has_space: Notifier,
     read_pos: usize,
     write_pos: usize,
+    closed: bool,
+}
+
+#[repr(C)]
+/// Slab allocator for variable length payloads
+///
+/// The data region is split into chunks of `ARENA_CHUNK_SIZE` bytes and a bitmap tracks which chunks are in use.
+/// Only the bitmap is protected by the lock, the data of a blob is only accessed by its current owner.
+struct Arena {
+    lock: libc::pthread_mutex_t,
+    /// Notified when chunks were freed, so allocations can wait for space
+    freed: Notifier,
+    used: [u64; ARENA_CHUNKS / 64],
+    data: [u8; ARENA_CHUNK_SIZE * ARENA_CHUNKS],
 }
 
 #[repr(C)]
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug, ShmSafe)]
+/// Reference to a variable length payload stored in the blob arena
+///
+/// `offset` is relative to the start of the arena, so it is valid in every process mapping the segment.
+/// A blob with `len == 0` does not occupy any space and freeing it is a no-op.
+///
+/// Ownership follows the message: blobs referenced by a `Request` belong to the server once it took the request
+/// and blobs referenced by a `Response` belong to the client.
+pub struct Blob {
+    pub offset: usize,
+    pub len: usize,
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 /// Operations supported by the HashTable
+///
+/// Stored as `u32` in `Request` and `Response`, see `TryFrom<u32>`
 pub enum Operation {
-    Read,
-    Insert,
-    Delete,
+    Read = 0,
+    Insert = 1,
+    Delete = 2,
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+/// Outcome of a request reported in its `Response`
+///
+/// Stored as `u32` in `Response`, see `TryFrom<u32>`
+///
+/// Mirrors `hashtable::Error` and adds codes for requests the server could not execute.
+pub enum Status {
+    Ok = 0,
+    BucketSizeZero = 1,
+    KeyExists = 2,
+    KeyMissing = 3,
+    /// The request references payloads outside of the arena
+    InvalidRequest = 4,
+    /// The server ran out of space for the response, the request can be retried later
+    Busy = 5,
+    /// The server does not support the operation
+    Unsupported = 6,
+    /// The server is shutting down and did not execute the request
+    ShuttingDown = 7,
+    /// The server failed for another reason
+    Internal = 8,
 }
 
 #[repr(C)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, ShmSafe)]
 /// Response sent to the client
 ///
-/// `val` should be `0` for `Delete` operations and on failure for `Read`
-pub struct Response<K: Clone, V: Clone> {
-    pub operation: Operation,
-    pub error: bool,
+/// `val` should be `0` for `Delete` operations and on failure for `Read`.
+/// `operation` and `status` are kept as plain integers, as the server might write values this build does not know.
+/// Use `Response::operation` and `Response::status` to decode them.
+pub struct Response<K: ShmSafe, V: ShmSafe> {
+    pub operation: u32,
+    pub status: u32,
     pub key: K,
     pub val: V,
     pub counter: usize,
 }
 
 #[repr(C)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, ShmSafe)]
 /// Request sent by the client
-pub struct Request<K: Clone, V: Clone> {
-    pub operation: Operation,
+///
+/// `operation` is kept as plain integer, as the client might write values this build does not know.
+/// Use `Request::operation` to decode it.
+///
+/// `priority` picks the lane of the request, higher lanes are served first.
+/// Priorities beyond the last lane of the queue go into the last lane.
+pub struct Request<K: ShmSafe, V: ShmSafe> {
+    pub operation: u32,
+    pub priority: u32,
     pub key: K,
     pub val: V,
     pub counter: usize,
 }
 
-impl<K: Clone, V: Clone> ShmQueue<K, V> {
+/// Slot of a ring reserved for writing in place, see `ShmQueue::request_reserve`
+///
+/// The entry becomes visible to the other side on `commit`, dropping the guard discards it.
+/// In a lock-free ring a discarded slot stays occupied until a reader skipped over it.
+/// The slot initially holds the entry of a previous round or zeroes, so every field should be written.
+pub struct Reservation<'a, T: ShmSafe> {
+    ring: &'a Ring<T>,
+    pos: usize,
+    slot: *mut T,
+    /// Eventfd signaled on `commit`
+    event: Option<&'a OwnedFd>,
+    /// Notified on `commit` in addition to the ring itself
+    notifier: Option<&'a Notifier>,
+}
+
+/// Slot of a ring borrowed for reading in place, see `ShmQueue::request_peek`
+///
+/// The slot is handed back to the writers when the guard is released or dropped, the entry is consumed either way.
+pub struct Peek<'a, T: ShmSafe> {
+    ring: &'a Ring<T>,
+    pos: usize,
+    slot: *const T,
+}
+
+impl<K: ShmSafe, V: ShmSafe> ShmQueue<K, V> {
     pub fn new(name: &str, server: bool) -> Result<Self, Error> {
+        Self::with_config(name, server, &QueueConfig::default())
+    }
+
+    /// Creates or connects to the queue `name`
+    ///
+    /// `config` is only used by the server, the client uses the settings found in the segment header
+    pub fn with_config(name: &str, server: bool, config: &QueueConfig) -> Result<Self, Error> {
         let flags = match server {
             true => shm::OFlags::CREATE | shm::OFlags::EXCL | shm::OFlags::RDWR,
             false => shm::OFlags::EXCL | shm::OFlags::RDWR,
         };
 
-        let fd = shm::open(name, flags, Mode::RUSR | Mode::WUSR)?;
+        let mode = Mode::RUSR | Mode::WUSR;
+        let fd = match shm::open(name, flags, mode) {
+            Err(rustix::io::Errno::EXIST) if server => {
+                reclaim(name, config.force)?;
+                shm::open(name, flags, mode)?
+            }
+            fd => fd?,
+        };
+
+        let res = match server {
+            true => set_permissions(&fd, config)
+                .and_then(|_| Self::map(fd, server, config, Some(name.to_string()))),
+            false => Self::map(fd, server, config, Some(name.to_string())),
+        };
+        if res.is_err() && server {
+            // Report why the setup failed, not whether the cleanup did
+            let _ = shm::unlink(name);
+        }
+        res
+    }
+
+    /// Creates a queue in anonymous memory for the server, which has no name that others could open
+    ///
+    /// The memory is handed to the client with `send_to`.
+    /// Its size is sealed, so neither side can make the other one fault by shrinking it.
+    pub fn anonymous(config: &QueueConfig) -> Result<Self, Error> {
+        let fd = memfd_create(
+            "hashtable-queue",
+            MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING,
+        )?;
+        let mut queue = Self::map(fd, true, config, None)?;
+        fcntl_add_seals(
+            &queue.fd,
+            SealFlags::SHRINK | SealFlags::GROW | SealFlags::SEAL,
+        )?;
+        if config.event_fd {
+            queue.event = Some(eventfd(0, EventfdFlags::CLOEXEC | EventfdFlags::NONBLOCK)?);
+        }
+        Ok(queue)
+    }
+
+    /// Connects to the queue in the memory of `fd` for the client, e.g. received with `receive_from`
+    pub fn from_fd(fd: OwnedFd) -> Result<Self, Error> {
+        Self::map(fd, false, &QueueConfig::default(), None)
+    }
+
+    /// Sends the memory of the queue and its eventfd, if any, to the client on the other end of `stream`
+    ///
+    /// The server should check who it is talking to first, see `peer_credentials`
+    pub fn send_to(&self, stream: &UnixStream) -> Result<(), Error> {
+        let mut fds = vec![self.fd.as_fd()];
+        fds.extend(self.event.as_ref().map(|event| event.as_fd()));
+        socket::send_fds(stream, &fds)
+    }
+
+    /// Connects to the queue the server sends over `stream`
+    ///
+    /// returns `Error::Rejected` if the server closed the connection instead
+    pub fn receive_from(stream: &UnixStream) -> Result<Self, Error> {
+        let mut fds = socket::recv_fds(stream)?.into_iter();
+        let mut queue = Self::from_fd(fds.next().ok_or(Error::Rejected)?)?;
+        queue.event = fds.next();
+        Ok(queue)
+    }
 
-        if server {
-            ftruncate(&fd, size_of::<SharedBufferInner<K, V>>() as u64)?;
+    /// Maps the segment of `fd`, which is set up first on the server side
+    fn map(
+        fd: OwnedFd,
+        server: bool,
+        config: &QueueConfig,
+        name: Option<String>,
+    ) -> Result<Self, Error> {
+        let capacity = |c: usize| match c {
+            1..=0xffff_ffff => Ok(c as u32),
+            _ => Err(Error::InvalidCapacity),
+        };
+        let (request_capacity, response_capacity) = (
+            capacity(config.request_capacity)?,
+            capacity(config.response_capacity)?,
+        );
+        if !(1..=MAX_LANES).contains(&config.lanes) {
+            return Err(Error::InvalidLanes);
         }
+        let lanes = config.lanes as u32;
+
+        let inode = fstat(&fd)?.st_ino;
 
-        let buffer_ptr = unsafe {
+        let size = match server {
+            true => {
+                let size =
+                    Layout::new::<K, V>(config.ring, request_capacity, response_capacity, lanes)
+                        .size;
+                ftruncate(&fd, size as u64)?;
+                size
+            }
+            false => {
+                // We can only look at the header if the segment is large enough to contain one
+                let size = fstat(&fd)?.st_size as usize;
+                if size < size_of::<SegmentHeader>() {
+                    return Err(Error::LayoutMismatch {
+                        field: "segment size",
+                        expected: size_of::<SegmentHeader>() as u64,
+                        found: size as u64,
+                    });
+                }
+                size
+            }
+        };
+
+        let ptr = unsafe {
             mmap(
                 null_mut(),
-                size_of::<SharedBufferInner<K, V>>(),
+                size,
                 ProtFlags::READ | ProtFlags::WRITE,
                 MapFlags::SHARED,
                 &fd,
                 0,
             )?
-        } as *mut SharedBufferInner<K, V>;
-        let buffer = SharedBuffer(buffer_ptr);
+        } as *mut u8;
 
-        if server {
-            buffer.init()?;
-        }
+        let buffer = match server {
+            true => {
+                let layout =
+                    Layout::new::<K, V>(config.ring, request_capacity, response_capacity, lanes);
+                let buffer = unsafe { SharedBuffer::new(ptr, &layout) };
+                unsafe { &mut *buffer.header }
+                    .owner
+                    .claim(Process::current());
+                unsafe { &*buffer.header }.server.claim(Process::current());
+                buffer.init(&layout)?;
+                buffer
+            }
+            false => {
+                let header = unsafe { &*(ptr as *const SegmentHeader) };
+                match header.validate::<K, V>(size) {
+                    Ok(layout) => {
+                        header.client.claim(Process::current());
+                        unsafe { SharedBuffer::new(ptr, &layout) }
+                    }
+                    Err(e) => {
+                        unsafe {
+                            munmap(ptr as *mut _, size)?;
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        };
 
         Ok(ShmQueue {
             buffer,
             server,
-            name: name.to_string(),
+            name,
+            fd,
+            inode,
+            unlinked: AtomicBool::new(false),
+            event: None,
         })
     }
 
-    pub fn request_put(&self, request: &Request<K, V>) -> Result<(), Error> {
-        self.buffer.request_put(request)
+    /// Number of requests that can be queued at once in each lane
+    pub fn request_capacity(&self) -> usize {
+        self.buffer.request_buffers[0].capacity()
     }
 
-    pub fn request_get(&self) -> Result<Request<K, V>, Error> {
-        self.buffer.request_get()
+    /// Number of priority lanes for requests
+    pub fn lanes(&self) -> usize {
+        self.buffer.request_buffers.len()
     }
 
-    pub fn response_put(&self, response: &Response<K, V>) -> Result<(), Error> {
-        self.buffer.response_put(response)
+    /// Number of responses that can be queued at once
+    pub fn response_capacity(&self) -> usize {
+        self.buffer.response_buffer.capacity()
     }
 
-    pub fn response_get(&self) -> Result<Response<K, V>, Error> {
-        self.buffer.response_get()
+    /// Implementation used for the rings
+    pub fn ring_kind(&self) -> RingKind {
+        self.buffer.request_buffers[0].kind()
     }
 
-    pub fn stop(&self) -> Result<(), Error> {
-        if self.server {
-            shm::unlink(&self.name)?
-        }
-
-        Ok(())
+    pub fn request_put(&self, request: &Request<K, V>) -> Result<(), Error> {
+        self.buffer.request_put(request, Wait::None)
     }
-}
 
-// Explicitly implement Send and Sync for our SharedBuffer as we implement the locking ourself where necessary.
-unsafe impl<K: Clone, V: Clone> Send for SharedBuffer<K, V> {}
-unsafe impl<K: Clone, V: Clone> Sync for SharedBuffer<K, V> {}
+    /// Puts a request into the buffer, waiting until there is space for it
+    pub fn request_put_blocking(&self, request: &Request<K, V>) -> Result<(), Error> {
+        self.buffer.request_put(request, Wait::Forever)
+    }
 
-// Note: all operations that mutate the memory are synchronized and the wrapped reference is assumed to be valid
-impl<K: Clone, V: Clone> SharedBuffer<K, V> {
-    pub fn request_put(&self, request: &Request<K, V>) -> Result<(), Error> {
-        let request_buffer = unsafe { &mut (*(self.0)).request_buffer };
-        request_buffer.put(request)
+    /// Puts a request into the buffer, waiting at most `timeout` for space
+    ///
+    /// returns `Error::BufferFull` if no space became available in time
+    pub fn request_put_timeout(
+        &self,
+        request: &Request<K, V>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        self.buffer.request_put(request, Wait::timeout(timeout))
     }
 
     pub fn request_get(&self) -> Result<Request<K, V>, Error> {
-        let request_buffer = unsafe { &mut (*(self.0)).request_buffer };
-        request_buffer.get()
+        self.buffer.request_get(Wait::Forever)
+    }
+
+    /// Gets a request from the buffer without waiting
+    ///
+    /// returns `Error::BufferEmpty` if there is no request
+    pub fn request_try_get(&self) -> Result<Request<K, V>, Error> {
+        self.buffer.request_get(Wait::None)
+    }
+
+    /// Gets a request from the buffer, waiting at most `timeout` for one
+    ///
+    /// returns `Error::Timeout` if no request arrived in time
+    pub fn request_get_timeout(&self, timeout: Duration) -> Result<Request<K, V>, Error> {
+        self.buffer.request_get(Wait::timeout(timeout))
     }
 
     pub fn response_put(&self, response: &Response<K, V>) -> Result<(), Error> {
-        let response_buffer = unsafe { &mut (*(self.0)).response_buffer };
-        response_buffer.put(response)
+        self.buffer
+            .response_put(response, Wait::None)
+            .inspect(|_| self.signal())
+    }
+
+    /// Puts a response into the buffer, waiting until there is space for it
+    pub fn response_put_blocking(&self, response: &Response<K, V>) -> Result<(), Error> {
+        self.buffer
+            .response_put(response, Wait::Forever)
+            .inspect(|_| self.signal())
+    }
+
+    /// Puts a response into the buffer, waiting at most `timeout` for space
+    ///
+    /// returns `Error::BufferFull` if no space became available in time
+    pub fn response_put_timeout(
+        &self,
+        response: &Response<K, V>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        self.buffer
+            .response_put(response, Wait::timeout(timeout))
+            .inspect(|_| self.signal())
     }
 
     pub fn response_get(&self) -> Result<Response<K, V>, Error> {
-        let response_buffer = unsafe { &mut (*(self.0)).response_buffer };
-        response_buffer.get()
+        self.buffer.response_get(Wait::Forever)
     }
 
-    pub fn init(&self) -> Result<(), Error> {
-        let request_buffer = unsafe { &mut (*(self.0)).request_buffer };
-        let response_buffer = unsafe { &mut (*(self.0)).response_buffer };
+    /// Gets a response from the buffer without waiting
+    ///
+    /// returns `Error::BufferEmpty` if there is no response
+    pub fn response_try_get(&self) -> Result<Response<K, V>, Error> {
+        self.buffer.response_get(Wait::None)
+    }
 
-        request_buffer.init()?;
-        response_buffer.init()?;
+    /// Gets a response from the buffer, waiting at most `timeout` for one
+    ///
+    /// returns `Error::Timeout` if no response arrived in time
+    pub fn response_get_timeout(&self, timeout: Duration) -> Result<Response<K, V>, Error> {
+        self.buffer.response_get(Wait::timeout(timeout))
+    }
 
-        Ok(())
+    /// Puts as many of `requests` into the buffer as fit at once
+    ///
+    /// returns the number of requests written from the start of `requests` or `Error::BufferFull` if none fit.
+    /// Only requests that go into the same lane as the first one are written at once.
+    pub fn request_put_many(&self, requests: &[Request<K, V>]) -> Result<usize, Error> {
+        self.buffer.request_put_many(requests, Wait::None)
     }
-}
 
-/// Initializes lock and configures it to be shareable between processes
-fn setup_lock(lock: &mut libc::pthread_mutex_t) -> Result<(), Error> {
-    let mut lock_attr = MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
+    /// Puts as many of `requests` into the buffer as fit at once, waiting until at least one fits
+    pub fn request_put_many_blocking(&self, requests: &[Request<K, V>]) -> Result<usize, Error> {
+        self.buffer.request_put_many(requests, Wait::Forever)
+    }
 
-    unsafe {
-        if libc::pthread_mutexattr_init(lock_attr.as_mut_ptr()) != 0 {
-            return Err(Error::MutexInit("Failed creating attrs".to_string()));
-        }
+    /// Puts as many of `requests` into the buffer as fit at once, waiting at most `timeout` for space
+    ///
+    /// returns `Error::BufferFull` if no space became available in time
+    pub fn request_put_many_timeout(
+        &self,
+        requests: &[Request<K, V>],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        self.buffer
+            .request_put_many(requests, Wait::timeout(timeout))
+    }
 
-        if libc::pthread_mutexattr_setpshared(lock_attr.as_mut_ptr(), PTHREAD_PROCESS_SHARED) != 0 {
-            return Err(Error::MutexInit(
-                "Failed to set shared process attr".to_string(),
-            ));
-        }
+    /// Gets up to `max` requests from the buffer at once, waiting until there is at least one
+    pub fn request_get_many(&self, max: usize) -> Result<Vec<Request<K, V>>, Error> {
+        self.buffer.request_get_many(max, Wait::Forever)
+    }
 
-        if libc::pthread_mutex_init(lock, lock_attr.as_mut_ptr()) != 0 {
-            return Err(Error::MutexInit("Failed to init mutex".to_string()));
-        }
+    /// Gets up to `max` requests from the buffer at once without waiting
+    ///
+    /// returns `Error::BufferEmpty` if there is no request
+    pub fn request_try_get_many(&self, max: usize) -> Result<Vec<Request<K, V>>, Error> {
+        self.buffer.request_get_many(max, Wait::None)
     }
 
-    Ok(())
-}
+    /// Gets up to `max` requests from the buffer at once, waiting at most `timeout` for the first one
+    ///
+    /// returns `Error::Timeout` if no request arrived in time
+    pub fn request_get_many_timeout(
+        &self,
+        max: usize,
+        timeout: Duration,
+    ) -> Result<Vec<Request<K, V>>, Error> {
+        self.buffer.request_get_many(max, Wait::timeout(timeout))
+    }
 
-/// Initializes condition and configures it to be shareable between processes
-fn setup_cond(cond: &mut libc::pthread_cond_t) -> Result<(), Error> {
-    let mut cond_attr = MaybeUninit::<libc::pthread_condattr_t>::uninit();
+    /// Puts as many of `responses` into the buffer as fit at once
+    ///
+    /// returns the number of responses written from the start of `responses` or `Error::BufferFull` if none fit
+    pub fn response_put_many(&self, responses: &[Response<K, V>]) -> Result<usize, Error> {
+        self.buffer
+            .response_put_many(responses, Wait::None)
+            .inspect(|_| self.signal())
+    }
 
-    unsafe {
-        if libc::pthread_condattr_init(cond_attr.as_mut_ptr()) != 0 {
-            return Err(Error::CondInit("Failed creating attrs".to_string()));
-        }
+    /// Puts as many of `responses` into the buffer as fit at once, waiting until at least one fits
+    pub fn response_put_many_blocking(&self, responses: &[Response<K, V>]) -> Result<usize, Error> {
+        self.buffer
+            .response_put_many(responses, Wait::Forever)
+            .inspect(|_| self.signal())
+    }
 
-        if libc::pthread_condattr_setpshared(cond_attr.as_mut_ptr(), PTHREAD_PROCESS_SHARED) != 0 {
-            return Err(Error::CondInit(
-                "Failed to set shared process attr".to_string(),
-            ));
-        }
+    /// Puts as many of `responses` into the buffer as fit at once, waiting at most `timeout` for space
+    ///
+    /// returns `Error::BufferFull` if no space became available in time
+    pub fn response_put_many_timeout(
+        &self,
+        responses: &[Response<K, V>],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        self.buffer
+            .response_put_many(responses, Wait::timeout(timeout))
+            .inspect(|_| self.signal())
+    }
 
-        if libc::pthread_cond_init(cond, cond_attr.as_mut_ptr()) != 0 {
-            return Err(Error::CondInit("Failed to init cond".to_string()));
-        }
+    /// Gets up to `max` responses from the buffer at once, waiting until there is at least one
+    pub fn response_get_many(&self, max: usize) -> Result<Vec<Response<K, V>>, Error> {
+        self.buffer.response_get_many(max, Wait::Forever)
     }
 
-    Ok(())
-}
+    /// Gets up to `max` responses from the buffer at once without waiting
+    ///
+    /// returns `Error::BufferEmpty` if there is no response
+    pub fn response_try_get_many(&self, max: usize) -> Result<Vec<Response<K, V>>, Error> {
+        self.buffer.response_get_many(max, Wait::None)
+    }
 
-impl<T: Clone> RingBuffer<T> {
-    /// Initializes fields and setups locking
+    /// Gets up to `max` responses from the buffer at once, waiting at most `timeout` for the first one
     ///
-    /// Should only be called once during initial setup of the data structure
-    pub fn init(&mut self) -> Result<(), Error> {
-        setup_lock(&mut self.lock)?;
-        setup_cond(&mut self.has_data)?;
-        self.read_pos = 0;
-        self.write_pos = 0;
-        Ok(())
+    /// returns `Error::Timeout` if no response arrived in time
+    pub fn response_get_many_timeout(
+        &self,
+        max: usize,
+        timeout: Duration,
+    ) -> Result<Vec<Response<K, V>>, Error> {
+        self.buffer.response_get_many(max, Wait::timeout(timeout))
     }
 
-    /// Puts data into buffer
+    /// Reserves the next free slot of the request ring to write a request directly into the shared memory
     ///
-    /// - waits for indefinitely for lock
-    /// - returns `Error::BufferFull` if there is no space to write
-    /// - notifies potential readers via condition of successful write
-    fn put(&mut self, data: &T) -> Result<(), Error> {
-        unsafe {
-            libc::pthread_mutex_lock(&mut self.lock);
-        }
+    /// returns `Error::BufferFull` if the ring is full.
+    /// For the locked ring the lock is held until the guard is gone, so the thread must not use the ring meanwhile.
+    /// The slot is in the lowest lane, see `request_reserve_lane` for the others.
+    pub fn request_reserve(&self) -> Result<Reservation<'_, Request<K, V>>, Error> {
+        self.request_reserve_lane(0)
+    }
 
-        // Check if we can write to buffer
-        if (self.write_pos + 1) % BUFFER_SIZE == self.read_pos {
-            unsafe {
-                libc::pthread_mutex_unlock(&mut self.lock);
-            }
-            return Err(Error::BufferFull);
-        }
+    /// Reserves the next free slot of the request ring, waiting until there is space
+    pub fn request_reserve_blocking(&self) -> Result<Reservation<'_, Request<K, V>>, Error> {
+        self.buffer.request_reserve(0, Wait::Forever)
+    }
+
+    /// Reserves the next free slot in the lane for requests of `priority`, see `request_reserve`
+    ///
+    /// The lane is fixed here, the priority written into the slot is ignored.
+    pub fn request_reserve_lane(
+        &self,
+        priority: u32,
+    ) -> Result<Reservation<'_, Request<K, V>>, Error> {
+        self.buffer.request_reserve(priority, Wait::None)
+    }
 
-        self.buffer[self.write_pos] = data.clone();
+    /// Borrows the oldest request of the highest lane in place, waiting until there is one
+    ///
+    /// For the locked ring the lock is held until the guard is gone, so the thread must not use the ring meanwhile.
+    pub fn request_peek(&self) -> Result<Peek<'_, Request<K, V>>, Error> {
+        self.buffer.request_peek(Wait::Forever)
+    }
 
-        self.write_pos = (self.write_pos + 1) % BUFFER_SIZE;
+    /// Borrows the oldest request of the highest lane in place without waiting
+    ///
+    /// returns `Error::BufferEmpty` if there is no request
+    pub fn request_try_peek(&self) -> Result<Peek<'_, Request<K, V>>, Error> {
+        self.buffer.request_peek(Wait::None)
+    }
 
-        unsafe {
-            libc::pthread_cond_signal(&mut self.has_data);
-            libc::pthread_mutex_unlock(&mut self.lock);
-        }
+    /// Reserves the next free slot of the response ring to write a response directly into the shared memory
+    ///
+    /// returns `Error::BufferFull` if the ring is full.
+    /// For the locked ring the lock is held until the guard is gone, so the thread must not use the ring meanwhile.
+    pub fn response_reserve(&self) -> Result<Reservation<'_, Response<K, V>>, Error> {
+        Reservation::new(&self.buffer.response_buffer, Wait::None)
+            .map(|reservation| reservation.with_event(self.event.as_ref()))
+    }
 
-        Ok(())
+    /// Reserves the next free slot of the response ring, waiting until there is space
+    pub fn response_reserve_blocking(&self) -> Result<Reservation<'_, Response<K, V>>, Error> {
+        Reservation::new(&self.buffer.response_buffer, Wait::Forever)
+            .map(|reservation| reservation.with_event(self.event.as_ref()))
     }
 
-    /// Gets data from buffer
+    /// Borrows the oldest response in place, waiting until there is one
     ///
-    /// - waits for indefinitely for lock
-    /// - waits for condition that new data was added if none is there
-    /// - notifies potential readers via condition if data is still left to read
-    fn get(&mut self) -> Result<T, Error> {
-        unsafe {
-            libc::pthread_mutex_lock(&mut self.lock);
-        }
+    /// For the locked ring the lock is held until the guard is gone, so the thread must not use the ring meanwhile.
+    pub fn response_peek(&self) -> Result<Peek<'_, Response<K, V>>, Error> {
+        Peek::new(&self.buffer.response_buffer, Wait::Forever)
+    }
 
-        // Check if we have something to read otherwise wait
-        while self.read_pos == self.write_pos {
-            unsafe {
-                libc::pthread_cond_wait(&mut self.has_data, &mut self.lock);
-            }
-        }
+    /// Borrows the oldest response in place without waiting
+    ///
+    /// returns `Error::BufferEmpty` if there is no response
+    pub fn response_try_peek(&self) -> Result<Peek<'_, Response<K, V>>, Error> {
+        Peek::new(&self.buffer.response_buffer, Wait::None)
+    }
 
-        let data = self.buffer[self.read_pos].clone();
+    /// Copies `data` into the blob arena
+    ///
+    /// returns `Error::ArenaFull` if there is no contiguous space left
+    pub fn blob_alloc(&self, data: &[u8]) -> Result<Blob, Error> {
+        self.buffer.blob_alloc(data, Wait::None)
+    }
 
-        self.read_pos = (self.read_pos + 1) % BUFFER_SIZE;
+    /// Copies `data` into the blob arena, waiting at most `timeout` for other blobs to be freed
+    ///
+    /// returns `Error::ArenaFull` if there is still no contiguous space left after `timeout`
+    pub fn blob_alloc_timeout(&self, data: &[u8], timeout: Duration) -> Result<Blob, Error> {
+        self.buffer.blob_alloc(data, Wait::timeout(timeout))
+    }
 
-        // Wake up other threads that still waits for data
-        if self.read_pos != self.write_pos {
-            unsafe {
-                libc::pthread_cond_signal(&mut self.has_data);
-            }
-        }
+    /// Copies the payload of `blob` out of the arena
+    pub fn blob_read(&self, blob: &Blob) -> Result<Vec<u8>, Error> {
+        self.buffer.blob_read(blob)
+    }
 
-        unsafe {
-            libc::pthread_mutex_unlock(&mut self.lock);
-        }
+    /// Releases the space of `blob` in the arena
+    pub fn blob_free(&self, blob: &Blob) -> Result<(), Error> {
+        self.buffer.blob_free(blob)
+    }
 
+    /// Copies the payload of `blob` out of the arena and releases it
+    pub fn blob_take(&self, blob: &Blob) -> Result<Vec<u8>, Error> {
+        let data = self.buffer.blob_read(blob)?;
+        self.buffer.blob_free(blob)?;
         Ok(data)
     }
-}
 
-#[cfg(test)]
+    /// Closes both rings and wakes up every thread waiting on them
+    ///
+    /// Afterwards puts fail with `Error::Closed` and gets return `Error::Closed` once the remaining entries were taken.
+    /// Meant for the server to shut down, the queue cannot be opened again. Can safely be called more than once.
+    pub fn close(&self) -> Result<(), Error> {
+        self.buffer.close().inspect(|_| self.signal())
+    }
 
-mod tests {
-    use super::*;
+    /// Closes only the request rings, so the server can still answer the requests that are already queued
+    pub fn close_requests(&self) -> Result<(), Error> {
+        self.buffer.close_requests()
+    }
 
-    const NAME: &str = "testing";
+    /// Eventfd that becomes readable when responses are available or the queue was closed
+    ///
+    /// Lets clients wait for responses in their own event loop (epoll, mio, ...) instead of blocking a thread.
+    /// Only present if the server enabled `QueueConfig::event_fd` and handed over the queue with `send_to`.
+    /// Call `clear_event` before taking the responses, so none that arrives meanwhile is missed.
+    pub fn event_fd(&self) -> Option<BorrowedFd<'_>> {
+        self.event.as_ref().map(|event| event.as_fd())
+    }
 
-    #[test]
-    fn setup() {
-        let ipc_server: ShmQueue<u32, u32> =
-            ShmQueue::new(NAME, true).expect("Failed to setup Queue");
+    /// Makes the eventfd unreadable until the next response arrives
+    pub fn clear_event(&self) -> Result<(), Error> {
+        let Some(event) = &self.event else {
+            return Ok(());
+        };
+        let mut count = [0u8; 8];
+        match read(event, &mut count) {
+            Ok(_) | Err(rustix::io::Errno::AGAIN) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn signal(&self) {
+        signal(self.event.as_ref());
+    }
+
+    /// Tells the other side that this process is still making progress
+    ///
+    /// Should be called about every `HEARTBEAT_INTERVAL`, independent of the traffic on the queue.
+    pub fn heartbeat(&self) {
+        self.own_heartbeat().beat();
+    }
+
+    /// Checks whether the other side is still running
+    ///
+    /// returns `Error::PeerDied` if its process exited and `Error::PeerTimeout` if it did not call `heartbeat`
+    /// within `timeout`. Succeeds as long as no client connected to the queue yet.
+    pub fn check_peer(&self, timeout: Duration) -> Result<(), Error> {
+        let header = unsafe { &*self.buffer.header };
+        match self.server {
+            true => header.client.check(timeout),
+            false => header.server.check(timeout),
+        }
+    }
+
+    fn own_heartbeat(&self) -> &Heartbeat {
+        let header = unsafe { &*self.buffer.header };
+        match self.server {
+            true => &header.server,
+            false => &header.client,
+        }
+    }
+
+    /// Removes the name of the queue, so no new client can connect
+    ///
+    /// Only has an effect on the server side and can safely be called more than once.
+    /// Already connected clients keep working until the server drops its queue.
+    /// If another server took over the name in the meantime, its segment is left alone.
+    pub fn stop(&self) -> Result<(), Error> {
+        let Some(name) = &self.name else {
+            return Ok(());
+        };
+        if self.server && !self.unlinked.swap(true, Ordering::AcqRel) {
+            let current =
+                shm::open(name, shm::OFlags::RDONLY, Mode::empty()).and_then(|fd| fstat(&fd));
+            match current {
+                Ok(stat) if stat.st_ino == self.inode => match shm::unlink(name) {
+                    Ok(()) | Err(rustix::io::Errno::NOENT) => (),
+                    Err(e) => return Err(e.into()),
+                },
+                Ok(_) | Err(rustix::io::Errno::NOENT) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<K: ShmSafe, V: ShmSafe> AsFd for ShmQueue<K, V> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl<K: ShmSafe, V: ShmSafe> Drop for ShmQueue<K, V> {
+    fn drop(&mut self) {
+        if self.server {
+            let _ = self.stop();
+            // Nobody may be left waiting on the locks we are about to destroy
+            let _ = self.close();
+            self.buffer.destroy();
+        }
+    }
+}
+
+// The guards contain raw pointers, so they are neither Send nor Sync.
+// That is required, as the lock of a locked ring has to be released by the thread that acquired it.
+impl<'a, T: ShmSafe> Reservation<'a, T> {
+    fn new(ring: &'a Ring<T>, wait: Wait) -> Result<Self, Error> {
+        let (pos, slot) = ring.reserve(wait)?;
+        Ok(Self {
+            ring,
+            pos,
+            slot,
+            event: None,
+            notifier: None,
+        })
+    }
+
+    fn with_event(mut self, event: Option<&'a OwnedFd>) -> Self {
+        self.event = event;
+        self
+    }
+
+    fn with_notifier(mut self, notifier: &'a Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Publishes the entry to the other side
+    pub fn commit(self) {
+        unsafe {
+            self.ring.commit(self.pos);
+        }
+        signal(self.event);
+        if let Some(notifier) = self.notifier {
+            notifier.notify_all();
+        }
+        std::mem::forget(self);
+    }
+}
+
+impl<T: ShmSafe> Deref for Reservation<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.slot }
+    }
+}
+
+impl<T: ShmSafe> DerefMut for Reservation<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.slot }
+    }
+}
+
+impl<T: ShmSafe> Drop for Reservation<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ring.abort(self.pos);
+        }
+    }
+}
+
+impl<'a, T: ShmSafe> Peek<'a, T> {
+    fn new(ring: &'a Ring<T>, wait: Wait) -> Result<Self, Error> {
+        let (pos, slot) = ring.peek(wait)?;
+        Ok(Self { ring, pos, slot })
+    }
+
+    /// Hands the slot back to the writers, same as dropping the guard
+    pub fn release(self) {}
+}
+
+impl<T: ShmSafe> Deref for Peek<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.slot }
+    }
+}
+
+impl<T: ShmSafe> Drop for Peek<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ring.release(self.pos);
+        }
+    }
+}
+
+// Explicitly implement Send and Sync for our SharedBuffer as we implement the locking ourself where necessary.
+unsafe impl<K: ShmSafe, V: ShmSafe> Send for SharedBuffer<K, V> {}
+unsafe impl<K: ShmSafe, V: ShmSafe> Sync for SharedBuffer<K, V> {}
+
+// Note: all operations that mutate the memory are synchronized and the wrapped pointers are assumed to be valid
+impl<K: ShmSafe, V: ShmSafe> SharedBuffer<K, V> {
+    /// Splits the mapping at `ptr` into its parts
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a mapping that is at least `layout.size` large
+    unsafe fn new(ptr: *mut u8, layout: &Layout) -> Self {
+        Self {
+            size: layout.size,
+            header: ptr as *mut SegmentHeader,
+            request_buffers: (0..layout.lanes as usize)
+                .map(|lane| {
+                    Ring::new(
+                        layout.ring,
+                        ptr.add(layout.request_offset + lane * layout.request_stride),
+                        layout.request_capacity as usize,
+                    )
+                })
+                .collect(),
+            skipped: (0..layout.lanes).map(|_| AtomicU32::new(0)).collect(),
+            spin: AdaptiveSpin::new(),
+            response_buffer: Ring::new(
+                layout.ring,
+                ptr.add(layout.response_offset),
+                layout.response_capacity as usize,
+            ),
+            arena: ptr.add(layout.arena_offset) as *mut Arena,
+        }
+    }
+
+    pub fn request_put(&self, request: &Request<K, V>, wait: Wait) -> Result<(), Error> {
+        self.request_buffers[self.lane(request.priority)]
+            .put(request, wait)
+            .inspect(|_| self.requests_changed())
+    }
+
+    pub fn request_get(&self, wait: Wait) -> Result<Request<K, V>, Error> {
+        self.request_take(wait, |ring, wait| ring.get(wait))
+    }
+
+    pub fn response_put(&self, response: &Response<K, V>, wait: Wait) -> Result<(), Error> {
+        self.response_buffer.put(response, wait)
+    }
+
+    pub fn response_get(&self, wait: Wait) -> Result<Response<K, V>, Error> {
+        self.response_buffer.get(wait)
+    }
+
+    pub fn request_put_many(&self, requests: &[Request<K, V>], wait: Wait) -> Result<usize, Error> {
+        let lane = requests
+            .first()
+            .map_or(0, |request| self.lane(request.priority));
+        let len = requests
+            .iter()
+            .take_while(|request| self.lane(request.priority) == lane)
+            .count();
+        self.request_buffers[lane]
+            .put_many(&requests[..len], wait)
+            .inspect(|_| self.requests_changed())
+    }
+
+    pub fn request_get_many(&self, max: usize, wait: Wait) -> Result<Vec<Request<K, V>>, Error> {
+        self.request_take(wait, |ring, wait| ring.get_many(max, wait))
+    }
+
+    pub fn request_reserve(
+        &self,
+        priority: u32,
+        wait: Wait,
+    ) -> Result<Reservation<'_, Request<K, V>>, Error> {
+        let header = unsafe { &*self.header };
+        Reservation::new(&self.request_buffers[self.lane(priority)], wait)
+            .map(|reservation| reservation.with_notifier(&header.requests_changed))
+    }
+
+    pub fn request_peek(&self, wait: Wait) -> Result<Peek<'_, Request<K, V>>, Error> {
+        self.request_take(wait, Peek::new)
+    }
+
+    /// Lane of requests with `priority`
+    fn lane(&self, priority: u32) -> usize {
+        (priority as usize).min(self.request_buffers.len() - 1)
+    }
+
+    /// Wakes up readers waiting for a request in any lane
+    fn requests_changed(&self) {
+        if self.request_buffers.len() > 1 {
+            unsafe { &*self.header }.requests_changed.notify_all();
+        }
+    }
+
+    /// Takes from the highest lane that has requests using `take`, waiting as long as `wait` allows
+    ///
+    /// A single lane is left to the ring, as it can wait on its own.
+    fn request_take<'a, R>(
+        &'a self,
+        wait: Wait,
+        take: impl Fn(&'a Ring<Request<K, V>>, Wait) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        if self.request_buffers.len() == 1 {
+            return take(&self.request_buffers[0], wait);
+        }
+
+        let header = unsafe { &*self.header };
+        header
+            .requests_changed
+            .wait_until(&wait, &self.spin, || self.request_try_lanes(&take))
+            .unwrap_or(match wait {
+                Wait::None => Err(Error::BufferEmpty),
+                _ => Err(Error::Timeout),
+            })
+    }
+
+    /// Tries every lane once without waiting, from the highest to the lowest
+    ///
+    /// A lane that was passed over `STARVATION_LIMIT` times while it had requests is tried first.
+    /// returns `None` if all lanes are empty and at least one is still open
+    fn request_try_lanes<'a, R>(
+        &'a self,
+        take: &impl Fn(&'a Ring<Request<K, V>>, Wait) -> Result<R, Error>,
+    ) -> Option<Result<R, Error>> {
+        let lanes = self.request_buffers.len();
+        let starving =
+            (0..lanes).find(|&lane| self.skipped[lane].load(Ordering::Relaxed) >= STARVATION_LIMIT);
+        let order = starving
+            .into_iter()
+            .chain((0..lanes).rev().filter(|&lane| Some(lane) != starving));
+
+        let mut closed = 0;
+        for lane in order {
+            match take(&self.request_buffers[lane], Wait::None) {
+                Err(Error::BufferEmpty) => self.skipped[lane].store(0, Ordering::Relaxed),
+                Err(Error::Closed) => {
+                    self.skipped[lane].store(0, Ordering::Relaxed);
+                    closed += 1;
+                }
+                res => {
+                    if res.is_ok() {
+                        self.skipped[lane].store(0, Ordering::Relaxed);
+                        // Lower lanes were not even looked at, empty ones are reset on the next try
+                        for lower in &self.skipped[..lane] {
+                            lower.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    return Some(res);
+                }
+            }
+        }
+
+        match closed == lanes {
+            true => Some(Err(Error::Closed)),
+            false => None,
+        }
+    }
+
+    pub fn response_put_many(
+        &self,
+        responses: &[Response<K, V>],
+        wait: Wait,
+    ) -> Result<usize, Error> {
+        self.response_buffer.put_many(responses, wait)
+    }
+
+    pub fn response_get_many(&self, max: usize, wait: Wait) -> Result<Vec<Response<K, V>>, Error> {
+        self.response_buffer.get_many(max, wait)
+    }
+
+    pub fn close(&self) -> Result<(), Error> {
+        self.close_requests()?;
+        self.response_buffer.close()
+    }
+
+    pub fn close_requests(&self) -> Result<(), Error> {
+        for ring in &self.request_buffers {
+            ring.close()?;
+        }
+        // Readers waiting on all lanes only see the rings closed when they are woken up
+        if self.request_buffers.len() > 1 {
+            unsafe { &*self.header }.requests_changed.notify_all();
+        }
+        Ok(())
+    }
+
+    pub fn blob_alloc(&self, data: &[u8], wait: Wait) -> Result<Blob, Error> {
+        let freed = unsafe { &(*self.arena).freed };
+        freed
+            .wait_until(&wait, &self.spin, || {
+                let arena = unsafe { &mut *self.arena };
+                match arena.alloc(data) {
+                    Err(Error::ArenaFull) => None,
+                    res => Some(res),
+                }
+            })
+            .unwrap_or(Err(Error::ArenaFull))
+    }
+
+    pub fn blob_read(&self, blob: &Blob) -> Result<Vec<u8>, Error> {
+        let arena = unsafe { &*self.arena };
+        arena.read(blob)
+    }
+
+    pub fn blob_free(&self, blob: &Blob) -> Result<(), Error> {
+        let arena = unsafe { &mut *self.arena };
+        arena.free(blob)
+    }
+
+    /// Destroys the locks
+    ///
+    /// Should only be called once by the owner of the segment, nobody may use them afterwards
+    pub fn destroy(&self) {
+        let arena = unsafe { &mut *self.arena };
+
+        for ring in &self.request_buffers {
+            ring.destroy();
+        }
+        self.response_buffer.destroy();
+        arena.destroy();
+    }
+
+    pub fn init(&self, layout: &Layout) -> Result<(), Error> {
+        let header = unsafe { &mut *self.header };
+        let arena = unsafe { &mut *self.arena };
+
+        for ring in &self.request_buffers {
+            ring.init()?;
+        }
+        self.response_buffer.init()?;
+        arena.init()?;
+        header.init::<K, V>(layout);
+
+        Ok(())
+    }
+}
+
+impl<K: ShmSafe, V: ShmSafe> Drop for SharedBuffer<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = munmap(self.header as *mut _, self.size);
+        }
+    }
+}
+
+/// Rounds `offset` up to the next multiple of `align`
+fn align_up(offset: usize, align: usize) -> usize {
+    offset.div_ceil(align) * align
+}
+
+impl Layout {
+    fn new<K: ShmSafe, V: ShmSafe>(
+        ring: RingKind,
+        request_capacity: u32,
+        response_capacity: u32,
+        lanes: u32,
+    ) -> Self {
+        let request_offset = align_up(
+            size_of::<SegmentHeader>(),
+            Ring::<Request<K, V>>::align(ring),
+        );
+        let request_stride = align_up(
+            Ring::<Request<K, V>>::size(ring, request_capacity as usize),
+            Ring::<Request<K, V>>::align(ring),
+        );
+        let response_offset = align_up(
+            request_offset + lanes as usize * request_stride,
+            Ring::<Response<K, V>>::align(ring),
+        );
+        let arena_offset = align_up(
+            response_offset + Ring::<Response<K, V>>::size(ring, response_capacity as usize),
+            align_of::<Arena>(),
+        );
+
+        Self {
+            ring,
+            request_capacity,
+            response_capacity,
+            lanes,
+            request_offset,
+            request_stride,
+            response_offset,
+            arena_offset,
+            size: arena_offset + size_of::<Arena>(),
+        }
+    }
+}
+
+/// Initializes lock and configures it to be shareable between processes
+fn setup_lock(lock: &mut libc::pthread_mutex_t) -> Result<(), Error> {
+    let mut lock_attr = MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
+
+    unsafe {
+        if libc::pthread_mutexattr_init(lock_attr.as_mut_ptr()) != 0 {
+            return Err(Error::MutexInit("Failed creating attrs".to_string()));
+        }
+
+        if libc::pthread_mutexattr_setpshared(lock_attr.as_mut_ptr(), PTHREAD_PROCESS_SHARED) != 0 {
+            return Err(Error::MutexInit(
+                "Failed to set shared process attr".to_string(),
+            ));
+        }
+
+        // The lock is released if the owning process dies, see `RingBuffer::lock`
+        if libc::pthread_mutexattr_setrobust(lock_attr.as_mut_ptr(), libc::PTHREAD_MUTEX_ROBUST)
+            != 0
+        {
+            return Err(Error::MutexInit("Failed to set robust attr".to_string()));
+        }
+
+        if libc::pthread_mutex_init(lock, lock_attr.as_mut_ptr()) != 0 {
+            return Err(Error::MutexInit("Failed to init mutex".to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the mode and group of `config` to a newly created segment
+///
+/// The mode passed on creation was reduced by the umask, so it is set again explicitly
+fn set_permissions(fd: &OwnedFd, config: &QueueConfig) -> Result<(), Error> {
+    fchmod(fd, Mode::from_raw_mode(config.mode))?;
+    if let Some(group) = config.group {
+        // Only `u32::MAX` is special, it leaves the group unchanged
+        fchown(fd, None, Some(unsafe { Gid::from_raw(group) }))?;
+    }
+    Ok(())
+}
+
+/// Removes the segment `name` left behind by a server that is no longer running
+///
+/// - returns `Error::SegmentInUse` if its owner is still alive and `Error::UnknownOwner` if none was recorded
+/// - `force` skips these checks, a server still using the segment keeps its mapping but loses the name
+fn reclaim(name: &str, force: bool) -> Result<(), Error> {
+    if !force {
+        let fd = shm::open(name, shm::OFlags::RDONLY, Mode::empty())?;
+        match SegmentOwner::read(&fd)? {
+            Some(owner) if owner.is_alive() => return Err(Error::SegmentInUse(owner.pid)),
+            Some(_) => (),
+            None => return Err(Error::UnknownOwner),
+        }
+    }
+
+    match shm::unlink(name) {
+        Ok(()) | Err(rustix::io::Errno::NOENT) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Current time on the monotonic clock
+fn now() -> libc::timespec {
+    let mut now = MaybeUninit::<libc::timespec>::uninit();
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, now.as_mut_ptr());
+        now.assume_init()
+    }
+}
+
+/// Makes `event` readable, if there is one
+///
+/// Fails only if the counter would overflow, in which case it is readable anyway.
+fn signal(event: Option<&OwnedFd>) {
+    if let Some(event) = event {
+        let _ = write(event, &1u64.to_ne_bytes());
+    }
+}
+
+/// Converts a point in time on the monotonic clock into milliseconds
+fn millis(time: libc::timespec) -> u64 {
+    time.tv_sec as u64 * 1000 + time.tv_nsec as u64 / 1_000_000
+}
+
+impl Wait {
+    /// Converts a relative `timeout` into a deadline on the monotonic clock
+    fn timeout(timeout: Duration) -> Self {
+        let now = now();
+
+        let nsec = now.tv_nsec as u64 + timeout.subsec_nanos() as u64;
+        Wait::Until(libc::timespec {
+            tv_sec: now
+                .tv_sec
+                .saturating_add(
+                    libc::time_t::try_from(timeout.as_secs()).unwrap_or(libc::time_t::MAX),
+                )
+                .saturating_add((nsec / 1_000_000_000) as libc::time_t),
+            tv_nsec: (nsec % 1_000_000_000) as _,
+        })
+    }
+
+    /// Checks if we should not or no longer wait
+    fn expired(&self) -> bool {
+        match self {
+            Wait::None => true,
+            Wait::Forever => false,
+            Wait::Until(deadline) => {
+                let now = now();
+                (now.tv_sec, now.tv_nsec) >= (deadline.tv_sec, deadline.tv_nsec)
+            }
+        }
+    }
+}
+
+impl<K: ShmSafe, V: ShmSafe> Request<K, V> {
+    pub fn new(operation: Operation, key: K, val: V, counter: usize) -> Self {
+        Self {
+            operation: operation.into(),
+            priority: 0,
+            key,
+            val,
+            counter,
+        }
+    }
+
+    /// Sets the priority lane of the request
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Decodes the operation written by the client
+    pub fn operation(&self) -> Result<Operation, Error> {
+        self.operation.try_into()
+    }
+}
+
+impl<K: ShmSafe, V: ShmSafe> Response<K, V> {
+    pub fn new(operation: Operation, status: Status, key: K, val: V, counter: usize) -> Self {
+        Self {
+            operation: operation.into(),
+            status: status.into(),
+            key,
+            val,
+            counter,
+        }
+    }
+
+    /// Decodes the operation of the request
+    pub fn operation(&self) -> Result<Operation, Error> {
+        self.operation.try_into()
+    }
+
+    /// Decodes the status written by the server
+    pub fn status(&self) -> Result<Status, Error> {
+        self.status.try_into()
+    }
+}
+
+impl From<Operation> for u32 {
+    fn from(operation: Operation) -> Self {
+        operation as u32
+    }
+}
+
+impl TryFrom<u32> for Operation {
+    type Error = Error;
+
+    fn try_from(raw: u32) -> Result<Self, Error> {
+        match raw {
+            0 => Ok(Operation::Read),
+            1 => Ok(Operation::Insert),
+            2 => Ok(Operation::Delete),
+            _ => Err(Error::InvalidOperation(raw)),
+        }
+    }
+}
+
+impl Status {
+    pub fn is_ok(&self) -> bool {
+        *self == Status::Ok
+    }
+}
+
+impl From<Status> for u32 {
+    fn from(status: Status) -> Self {
+        status as u32
+    }
+}
+
+impl TryFrom<u32> for Status {
+    type Error = Error;
+
+    fn try_from(raw: u32) -> Result<Self, Error> {
+        match raw {
+            0 => Ok(Status::Ok),
+            1 => Ok(Status::BucketSizeZero),
+            2 => Ok(Status::KeyExists),
+            3 => Ok(Status::KeyMissing),
+            4 => Ok(Status::InvalidRequest),
+            5 => Ok(Status::Busy),
+            6 => Ok(Status::Unsupported),
+            7 => Ok(Status::ShuttingDown),
+            8 => Ok(Status::Internal),
+            _ => Err(Error::InvalidStatus(raw)),
+        }
+    }
+}
+
+impl From<hashtable::Error> for Status {
+    fn from(e: hashtable::Error) -> Self {
+        match e {
+            hashtable::Error::BucketSizeZero => Status::BucketSizeZero,
+            hashtable::Error::KeyExists => Status::KeyExists,
+            hashtable::Error::KeyMissing => Status::KeyMissing,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Ok => write!(f, "ok"),
+            Status::BucketSizeZero => write!(f, "number of buckets cannot be zero"),
+            Status::KeyExists => write!(f, "key already exists"),
+            Status::KeyMissing => write!(f, "key is missing"),
+            Status::InvalidRequest => write!(f, "request is invalid"),
+            Status::Busy => write!(f, "server is busy"),
+            Status::Unsupported => write!(f, "operation is not supported"),
+            Status::ShuttingDown => write!(f, "server is shutting down"),
+            Status::Internal => write!(f, "internal server error"),
+        }
+    }
+}
+
+impl RingKind {
+    fn to_raw(self) -> u32 {
+        match self {
+            RingKind::Locked => 0,
+            RingKind::LockFree => 1,
+        }
+    }
+
+    fn from_raw(raw: u32) -> Result<Self, Error> {
+        match raw {
+            0 => Ok(RingKind::Locked),
+            1 => Ok(RingKind::LockFree),
+            _ => Err(Error::UnknownRingKind(raw)),
+        }
+    }
+}
+
+// The locked ring needs one slot more than its capacity, the lock-free one does not
+impl<T: ShmSafe> Ring<T> {
+    /// Required alignment of a ring of `kind`
+    fn align(kind: RingKind) -> usize {
+        match kind {
+            RingKind::Locked => RingBuffer::<T>::align(),
+            RingKind::LockFree => lock_free::RingBuffer::<T>::align(),
+        }
+    }
+
+    /// Size of a ring of `kind` holding `capacity` entries
+    fn size(kind: RingKind, capacity: usize) -> usize {
+        match kind {
+            RingKind::Locked => RingBuffer::<T>::size(capacity + 1),
+            RingKind::LockFree => lock_free::RingBuffer::<T>::size(capacity),
+        }
+    }
+
+    /// Creates a handle for the ring at `ptr`
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be aligned to `Self::align(kind)` and valid for `Self::size(kind, capacity)` bytes
+    unsafe fn new(kind: RingKind, ptr: *mut u8, capacity: usize) -> Self {
+        match kind {
+            RingKind::Locked => Ring::Locked(RingBuffer::new(ptr, capacity + 1)),
+            RingKind::LockFree => Ring::LockFree(lock_free::RingBuffer::new(ptr, capacity)),
+        }
+    }
+
+    fn kind(&self) -> RingKind {
+        match self {
+            Ring::Locked(_) => RingKind::Locked,
+            Ring::LockFree(_) => RingKind::LockFree,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            Ring::Locked(ring) => ring.len - 1,
+            Ring::LockFree(ring) => ring.capacity(),
+        }
+    }
+
+    fn init(&self) -> Result<(), Error> {
+        match self {
+            Ring::Locked(ring) => ring.init(),
+            Ring::LockFree(ring) => ring.init(),
+        }
+    }
+
+    fn destroy(&self) {
+        match self {
+            Ring::Locked(ring) => ring.destroy(),
+            // Atomics and futexes do not need to be cleaned up
+            Ring::LockFree(_) => (),
+        }
+    }
+
+    fn put(&self, data: &T, wait: Wait) -> Result<(), Error> {
+        match self {
+            Ring::Locked(ring) => ring.put(data, wait),
+            Ring::LockFree(ring) => ring.put(data, wait),
+        }
+    }
+
+    fn get(&self, wait: Wait) -> Result<T, Error> {
+        match self {
+            Ring::Locked(ring) => ring.get(wait),
+            Ring::LockFree(ring) => ring.get(wait),
+        }
+    }
+
+    /// Claims the next free slot, returns its position and data
+    ///
+    /// The slot has to be passed on with `commit` or `abort`
+    fn reserve(&self, wait: Wait) -> Result<(usize, *mut T), Error> {
+        match self {
+            // The locked ring keeps the position in the shared memory
+            Ring::Locked(ring) => ring.reserve(wait).map(|slot| (0, slot)),
+            Ring::LockFree(ring) => ring.reserve(wait).map(|pos| (pos, ring.data(pos))),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `pos` must come from a successful `reserve` of the calling thread and is passed on only once
+    unsafe fn commit(&self, pos: usize) {
+        match self {
+            Ring::Locked(ring) => ring.commit(),
+            Ring::LockFree(ring) => ring.commit(pos),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `pos` must come from a successful `reserve` of the calling thread and is passed on only once
+    unsafe fn abort(&self, pos: usize) {
+        match self {
+            Ring::Locked(ring) => ring.abort(),
+            Ring::LockFree(ring) => ring.abort(pos),
+        }
+    }
+
+    /// Claims the oldest slot with data, returns its position and data
+    ///
+    /// The slot has to be freed with `release`
+    fn peek(&self, wait: Wait) -> Result<(usize, *const T), Error> {
+        match self {
+            Ring::Locked(ring) => ring.peek(wait).map(|slot| (0, slot)),
+            Ring::LockFree(ring) => ring.peek(wait).map(|pos| (pos, ring.data(pos) as *const T)),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `pos` must come from a successful `peek` of the calling thread and is released only once
+    unsafe fn release(&self, pos: usize) {
+        match self {
+            Ring::Locked(ring) => ring.release(),
+            Ring::LockFree(ring) => ring.release(pos),
+        }
+    }
+
+    fn put_many(&self, data: &[T], wait: Wait) -> Result<usize, Error> {
+        match self {
+            Ring::Locked(ring) => ring.put_many(data, wait),
+            Ring::LockFree(ring) => ring.put_many(data, wait),
+        }
+    }
+
+    fn get_many(&self, max: usize, wait: Wait) -> Result<Vec<T>, Error> {
+        match self {
+            Ring::Locked(ring) => ring.get_many(max, wait),
+            Ring::LockFree(ring) => ring.get_many(max, wait),
+        }
+    }
+
+    fn close(&self) -> Result<(), Error> {
+        match self {
+            Ring::Locked(ring) => ring.close(),
+            Ring::LockFree(ring) => {
+                ring.close();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<T: ShmSafe> RingBuffer<T> {
+    /// Offset of the first slot from the start of the ring
+    fn slots_offset() -> usize {
+        align_up(size_of::<RingBufferInner>(), align_of::<T>())
+    }
+
+    /// Required alignment of a ring
+    fn align() -> usize {
+        align_of::<RingBufferInner>().max(align_of::<T>())
+    }
+
+    /// Size of a ring with `len` slots
+    fn size(len: usize) -> usize {
+        Self::slots_offset() + len * size_of::<T>()
+    }
+
+    /// Creates a handle for the ring at `ptr`
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be aligned to `Self::align()` and valid for `Self::size(len)` bytes
+    unsafe fn new(ptr: *mut u8, len: usize) -> Self {
+        Self {
+            inner: ptr as *mut RingBufferInner,
+            slots: ptr.add(Self::slots_offset()) as *mut T,
+            len,
+            spin: AdaptiveSpin::new(),
+        }
+    }
+
+    /// Initializes fields and setups locking
+    ///
+    /// Should only be called once during initial setup of the data structure
+    pub fn init(&self) -> Result<(), Error> {
+        let inner = unsafe { &mut *self.inner };
+        setup_lock(&mut inner.lock)?;
+        inner.has_data.init();
+        inner.has_space.init();
+        inner.read_pos = 0;
+        inner.write_pos = 0;
+        inner.closed = false;
+        Ok(())
+    }
+
+    /// Destroys the lock
+    ///
+    /// Should only be called once by the owner of the segment
+    pub fn destroy(&self) {
+        let inner = unsafe { &mut *self.inner };
+        unsafe {
+            libc::pthread_mutex_destroy(&mut inner.lock);
+        }
+    }
+
+    /// Futex notified when data was added
+    fn has_data(&self) -> &Notifier {
+        unsafe { &(*self.inner).has_data }
+    }
+
+    /// Futex notified when space was freed
+    fn has_space(&self) -> &Notifier {
+        unsafe { &(*self.inner).has_space }
+    }
+
+    /// Acquires the lock of the ring
+    ///
+    /// - waits for indefinitely for lock
+    /// - recovers the ring and returns `Error::OwnerDied` if the previous owner died while holding the lock
+    #[allow(clippy::mut_from_ref)]
+    fn lock(&self) -> Result<&mut RingBufferInner, Error> {
+        let inner = unsafe { &mut *self.inner };
+        match unsafe { libc::pthread_mutex_lock(&mut inner.lock) } {
+            0 => Ok(inner),
+            libc::EOWNERDEAD => Err(self.recover(inner)),
+            rc => Err(rustix::io::Errno::from_raw_os_error(rc).into()),
+        }
+    }
+
+    /// Releases the lock acquired by `lock`
+    fn unlock(&self, inner: &mut RingBufferInner) {
+        unsafe {
+            libc::pthread_mutex_unlock(&mut inner.lock);
+        }
+    }
+
+    /// Makes the ring consistent again after a process died while holding the lock and releases the lock
+    ///
+    /// Writes and reads only become visible by updating a single position, so the only thing to repair are
+    /// positions outside of the ring. In that case the content of the ring is dropped.
+    /// All waiters are woken up, as the ring might have changed for them.
+    fn recover(&self, inner: &mut RingBufferInner) -> Error {
+        if inner.read_pos >= self.len || inner.write_pos >= self.len {
+            inner.read_pos = 0;
+            inner.write_pos = 0;
+        }
+
+        unsafe {
+            libc::pthread_mutex_consistent(&mut inner.lock);
+        }
+        self.unlock(inner);
+        self.has_data().notify_all();
+        self.has_space().notify_all();
+
+        Error::OwnerDied
+    }
+
+    /// Acquires the lock once `ready` holds, waiting on `notifier` according to `wait` in between
+    ///
+    /// returns `None` if `ready` still does not hold when `wait` expired
+    #[allow(clippy::mut_from_ref)]
+    fn lock_when(
+        &self,
+        notifier: &Notifier,
+        wait: Wait,
+        ready: impl Fn(&RingBufferInner) -> bool,
+    ) -> Option<Result<&mut RingBufferInner, Error>> {
+        notifier.wait_until(&wait, &self.spin, || match self.lock() {
+            Ok(inner) if ready(inner) => Some(Ok(inner)),
+            Ok(inner) => {
+                self.unlock(inner);
+                None
+            }
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Number of entries in the ring
+    fn used(&self, inner: &RingBufferInner) -> usize {
+        (inner.write_pos + self.len - inner.read_pos) % self.len
+    }
+
+    /// Number of entries that can still be written
+    fn free(&self, inner: &RingBufferInner) -> usize {
+        self.len - 1 - self.used(inner)
+    }
+
+    /// Acquires the lock, waiting according to `wait` until there is space in the ring
+    ///
+    /// - returns `Error::BufferFull` if there is still no space to write
+    /// - returns `Error::Closed` if the ring was closed, even if there is space
+    /// - returns `Error::OwnerDied` if the lock had to be recovered
+    /// - the lock is only held if no error is returned
+    #[allow(clippy::mut_from_ref)]
+    fn lock_for_space(&self, wait: Wait) -> Result<&mut RingBufferInner, Error> {
+        let inner = self
+            .lock_when(self.has_space(), wait, |inner| {
+                inner.closed || self.free(inner) > 0
+            })
+            .unwrap_or(Err(Error::BufferFull))?;
+
+        if inner.closed {
+            self.unlock(inner);
+            return Err(Error::Closed);
+        }
+
+        Ok(inner)
+    }
+
+    /// Acquires the lock, waiting according to `wait` until there is data in the ring
+    ///
+    /// - returns `Error::BufferEmpty` when not waiting or `Error::Timeout` after the deadline if there is still no data
+    /// - returns `Error::Closed` if the ring was closed and all remaining data was read
+    /// - returns `Error::OwnerDied` if the lock had to be recovered
+    /// - the lock is only held if no error is returned
+    #[allow(clippy::mut_from_ref)]
+    fn lock_for_data(&self, wait: Wait) -> Result<&mut RingBufferInner, Error> {
+        let inner = self
+            .lock_when(self.has_data(), wait, |inner| {
+                inner.closed || self.used(inner) > 0
+            })
+            .unwrap_or(match wait {
+                Wait::None => Err(Error::BufferEmpty),
+                _ => Err(Error::Timeout),
+            })?;
+
+        if self.used(inner) == 0 {
+            self.unlock(inner);
+            return Err(Error::Closed);
+        }
+
+        Ok(inner)
+    }
+
+    /// Acquires the lock and returns the next free slot
+    ///
+    /// - waits according to `wait` until space was freed if the buffer is full, see `lock_for_space`
+    /// - on success the lock stays held until `commit` or `abort` is called by the same thread
+    fn reserve(&self, wait: Wait) -> Result<*mut T, Error> {
+        let inner = self.lock_for_space(wait)?;
+        Ok(unsafe { self.slots.add(inner.write_pos) })
+    }
+
+    /// Publishes the slot returned by `reserve`, releases the lock and notifies a potential reader
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by a successful `reserve` of the calling thread
+    unsafe fn commit(&self) {
+        let inner = &mut *self.inner;
+        inner.write_pos = (inner.write_pos + 1) % self.len;
+        self.unlock(inner);
+        self.has_data().notify();
+    }
+
+    /// Releases the lock held by `reserve` without publishing anything
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by a successful `reserve` of the calling thread
+    unsafe fn abort(&self) {
+        self.unlock(&mut *self.inner);
+    }
+
+    /// Puts data into buffer
+    ///
+    /// - waits like `reserve` for the lock and space
+    /// - returns `Error::OwnerDied` if the lock had to be recovered, the data was not written then
+    /// - notifies a potential reader of the successful write
+    fn put(&self, data: &T, wait: Wait) -> Result<(), Error> {
+        let slot = self.reserve(wait)?;
+        unsafe {
+            *slot = *data;
+            self.commit();
+        }
+        Ok(())
+    }
+
+    /// Puts as many entries of `data` into the buffer as fit, under a single acquisition of the lock
+    ///
+    /// - waits like `put` until at least one entry fits
+    /// - returns the number of entries written from the start of `data`
+    /// - notifies all potential readers if more than one entry was written
+    fn put_many(&self, data: &[T], wait: Wait) -> Result<usize, Error> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let inner = self.lock_for_space(wait)?;
+
+        let count = self.free(inner).min(data.len());
+        for entry in &data[..count] {
+            unsafe {
+                *self.slots.add(inner.write_pos) = *entry;
+            }
+            inner.write_pos = (inner.write_pos + 1) % self.len;
+        }
+        self.unlock(inner);
+
+        match count {
+            1 => self.has_data().notify(),
+            _ => self.has_data().notify_all(),
+        }
+
+        Ok(count)
+    }
+
+    /// Acquires the lock and returns the oldest slot with data
+    ///
+    /// - waits according to `wait` until new data was added if none is there, see `lock_for_data`
+    /// - on success the lock stays held until `release` is called by the same thread
+    fn peek(&self, wait: Wait) -> Result<*const T, Error> {
+        let inner = self.lock_for_data(wait)?;
+        Ok(unsafe { self.slots.add(inner.read_pos) })
+    }
+
+    /// Frees the slot returned by `peek` and releases the lock
+    ///
+    /// - notifies another potential reader if data is still left to read
+    /// - notifies a potential writer that space was freed
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by a successful `peek` of the calling thread
+    unsafe fn release(&self) {
+        let inner = &mut *self.inner;
+        inner.read_pos = (inner.read_pos + 1) % self.len;
+        let left = inner.read_pos != inner.write_pos;
+        self.unlock(inner);
+
+        // Wake up other threads that still wait for data
+        if left {
+            self.has_data().notify();
+        }
+        self.has_space().notify();
+    }
+
+    /// Gets data from buffer
+    ///
+    /// - waits like `peek` for the lock and data
+    /// - returns `Error::OwnerDied` if the lock had to be recovered, nothing was read then
+    fn get(&self, wait: Wait) -> Result<T, Error> {
+        let slot = self.peek(wait)?;
+        unsafe {
+            let data = *slot;
+            self.release();
+            Ok(data)
+        }
+    }
+
+    /// Gets up to `max` entries from the buffer, under a single acquisition of the lock
+    ///
+    /// - waits like `get` until at least one entry is there, unless `max` is `0`
+    /// - notifies all potential writers if more than one entry was read
+    fn get_many(&self, max: usize, wait: Wait) -> Result<Vec<T>, Error> {
+        if max == 0 {
+            return Ok(vec![]);
+        }
+
+        let inner = self.lock_for_data(wait)?;
+
+        let count = self.used(inner).min(max);
+        let mut data = Vec::with_capacity(count);
+        for _ in 0..count {
+            data.push(unsafe { *self.slots.add(inner.read_pos) });
+            inner.read_pos = (inner.read_pos + 1) % self.len;
+        }
+        let left = inner.read_pos != inner.write_pos;
+        self.unlock(inner);
+
+        // Wake up other threads that still wait for data
+        if left {
+            self.has_data().notify();
+        }
+        match count {
+            1 => self.has_space().notify(),
+            _ => self.has_space().notify_all(),
+        }
+
+        Ok(data)
+    }
+
+    /// Marks the ring as closed and wakes up all waiting readers and writers
+    ///
+    /// - waits for indefinitely for lock
+    fn close(&self) -> Result<(), Error> {
+        let inner = loop {
+            match self.lock() {
+                // The ring was recovered and the lock released again
+                Err(Error::OwnerDied) => (),
+                res => break res?,
+            }
+        };
+
+        inner.closed = true;
+        self.unlock(inner);
+
+        self.has_data().notify_all();
+        self.has_space().notify_all();
+
+        Ok(())
+    }
+}
+
+impl SegmentOwner {
+    /// Records `owner` as the creator of the segment
+    ///
+    /// Should be called right after the segment was created, before anything else is set up
+    fn claim(&mut self, owner: Process) {
+        self.version = PROTOCOL_VERSION;
+        self.pid = owner.pid;
+        self.start_time = owner.start_time;
+    }
+
+    /// Process that created the segment
+    fn process(&self) -> Process {
+        Process {
+            pid: self.pid,
+            start_time: self.start_time,
+        }
+    }
+
+    /// Reads the owner of an existing segment without mapping it
+    ///
+    /// returns `None` if the segment is too small or no owner was recorded (yet)
+    fn read(fd: &OwnedFd) -> Result<Option<Process>, Error> {
+        let mut buf = [0u8; size_of::<SegmentOwner>()];
+        if pread(fd, &mut buf, 0)? < buf.len() {
+            return Ok(None);
+        }
+        // Every field is a plain integer, so any content is a valid owner
+        let owner = unsafe { ptr::read_unaligned(buf.as_ptr() as *const SegmentOwner) };
+
+        Ok(match owner.version >= OWNER_VERSION && owner.pid != 0 {
+            true => Some(owner.process()),
+            false => None,
+        })
+    }
+}
+
+impl SegmentHeader {
+    /// Records the layout of this build
+    ///
+    /// Should be called after everything else in the segment is set up
+    fn init<K: ShmSafe, V: ShmSafe>(&mut self, layout: &Layout) {
+        self.key_size = size_of::<K>() as u32;
+        self.key_align = align_of::<K>() as u32;
+        self.value_size = size_of::<V>() as u32;
+        self.value_align = align_of::<V>() as u32;
+        self.ring_kind = layout.ring.to_raw();
+        self.request_capacity = layout.request_capacity;
+        self.response_capacity = layout.response_capacity;
+        self.lanes = layout.lanes;
+        self.requests_changed.init();
+        self.segment_size = layout.size as u64;
+        self.owner.magic.store(MAGIC, Ordering::Release);
+    }
+
+    /// Compares the recorded layout against the layout of this build and the actual `size` of the segment
+    ///
+    /// returns the layout described by the header
+    fn validate<K: ShmSafe, V: ShmSafe>(&self, size: usize) -> Result<Layout, Error> {
+        let check = |field: &'static str, expected: u64, found: u64| match expected == found {
+            true => Ok(()),
+            false => Err(Error::LayoutMismatch {
+                field,
+                expected,
+                found,
+            }),
+        };
+
+        check("magic", MAGIC, self.owner.magic.load(Ordering::Acquire))?;
+        check(
+            "protocol version",
+            PROTOCOL_VERSION as u64,
+            self.owner.version as u64,
+        )?;
+        check("key size", size_of::<K>() as u64, self.key_size as u64)?;
+        check(
+            "key alignment",
+            align_of::<K>() as u64,
+            self.key_align as u64,
+        )?;
+        check("value size", size_of::<V>() as u64, self.value_size as u64)?;
+        check(
+            "value alignment",
+            align_of::<V>() as u64,
+            self.value_align as u64,
+        )?;
+        if self.request_capacity == 0 || self.response_capacity == 0 {
+            return Err(Error::InvalidCapacity);
+        }
+        if !(1..=MAX_LANES as u32).contains(&self.lanes) {
+            return Err(Error::InvalidLanes);
+        }
+        let layout = Layout::new::<K, V>(
+            RingKind::from_raw(self.ring_kind)?,
+            self.request_capacity,
+            self.response_capacity,
+            self.lanes,
+        );
+        check("segment size", layout.size as u64, self.segment_size)?;
+        check("segment size", self.segment_size, size as u64)?;
+
+        Ok(layout)
+    }
+}
+
+impl Heartbeat {
+    /// Records `process` as this side and beats once
+    fn claim(&self, process: Process) {
+        self.start_time.store(process.start_time, Ordering::Relaxed);
+        self.beat();
+        self.pid.store(process.pid, Ordering::Release);
+    }
+
+    fn beat(&self) {
+        self.beat.store(millis(now()), Ordering::Release);
+    }
+
+    /// See `ShmQueue::check_peer`
+    fn check(&self, timeout: Duration) -> Result<(), Error> {
+        let pid = self.pid.load(Ordering::Acquire);
+        if pid == 0 {
+            return Ok(());
+        }
+        let process = Process {
+            pid,
+            start_time: self.start_time.load(Ordering::Relaxed),
+        };
+        if !process.is_alive() {
+            return Err(Error::PeerDied(pid));
+        }
+
+        let silence = millis(now()).saturating_sub(self.beat.load(Ordering::Acquire));
+        match silence > timeout.as_millis() as u64 {
+            true => Err(Error::PeerTimeout(pid)),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Arena {
+    /// Initializes the lock and marks all chunks as free
+    ///
+    /// Should only be called once during initial setup of the data structure
+    pub fn init(&mut self) -> Result<(), Error> {
+        setup_lock(&mut self.lock)?;
+        self.freed.init();
+        self.used = [0; ARENA_CHUNKS / 64];
+        Ok(())
+    }
+
+    /// Destroys the lock
+    ///
+    /// Should only be called once by the owner of the segment
+    pub fn destroy(&mut self) {
+        unsafe {
+            libc::pthread_mutex_destroy(&mut self.lock);
+        }
+    }
+
+    /// Acquires the lock of the arena
+    ///
+    /// - waits for indefinitely for lock
+    /// - returns `Error::OwnerDied` after releasing the lock again if the previous owner died while holding it.
+    ///   Chunks that were allocated or freed by the dead process at that time might stay in use.
+    fn lock(&mut self) -> Result<(), Error> {
+        match unsafe { libc::pthread_mutex_lock(&mut self.lock) } {
+            0 => Ok(()),
+            libc::EOWNERDEAD => {
+                unsafe {
+                    libc::pthread_mutex_consistent(&mut self.lock);
+                    libc::pthread_mutex_unlock(&mut self.lock);
+                }
+                Err(Error::OwnerDied)
+            }
+            rc => Err(rustix::io::Errno::from_raw_os_error(rc).into()),
+        }
+    }
+
+    fn is_used(&self, chunk: usize) -> bool {
+        self.used[chunk / 64] & (1 << (chunk % 64)) != 0
+    }
+
+    fn mark(&mut self, start: usize, count: usize, used: bool) {
+        for chunk in start..start + count {
+            match used {
+                true => self.used[chunk / 64] |= 1 << (chunk % 64),
+                false => self.used[chunk / 64] &= !(1 << (chunk % 64)),
+            }
+        }
+    }
+
+    /// Returns the first chunk and number of chunks covered by `blob`
+    ///
+    /// Blobs come from the other process, so they are checked before they are used.
+    fn chunks(&self, blob: &Blob) -> Result<(usize, usize), Error> {
+        let invalid = Error::InvalidBlob {
+            offset: blob.offset,
+            len: blob.len,
+        };
+        if blob.offset % ARENA_CHUNK_SIZE != 0 {
+            return Err(invalid);
+        }
+        match blob.offset.checked_add(blob.len) {
+            Some(end) if end <= self.data.len() => (),
+            _ => return Err(invalid),
+        }
+
+        Ok((
+            blob.offset / ARENA_CHUNK_SIZE,
+            blob.len.div_ceil(ARENA_CHUNK_SIZE),
+        ))
+    }
+
+    /// Reserves enough contiguous chunks for `data` and copies it into them
+    ///
+    /// - waits for indefinitely for lock
+    /// - returns `Error::ArenaFull` if no large enough free run of chunks exists
+    fn alloc(&mut self, data: &[u8]) -> Result<Blob, Error> {
+        if data.is_empty() {
+            return Ok(Blob::default());
+        }
+        let count = data.len().div_ceil(ARENA_CHUNK_SIZE);
+
+        self.lock()?;
+
+        // First fit search for a free run of chunks
+        let mut start = None;
+        let mut run = 0;
+        for chunk in 0..ARENA_CHUNKS {
+            if self.is_used(chunk) {
+                run = 0;
+                continue;
+            }
+            run += 1;
+            if run == count {
+                start = Some(chunk + 1 - count);
+                break;
+            }
+        }
+
+        if let Some(start) = start {
+            self.mark(start, count, true);
+        }
+
+        unsafe {
+            libc::pthread_mutex_unlock(&mut self.lock);
+        }
+
+        let start = start.ok_or(Error::ArenaFull)?;
+        let offset = start * ARENA_CHUNK_SIZE;
+        // The chunks are now owned by us, so the copy does not need the lock
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+
+        Ok(Blob {
+            offset,
+            len: data.len(),
+        })
+    }
+
+    /// Copies the payload of `blob` out of the arena
+    fn read(&self, blob: &Blob) -> Result<Vec<u8>, Error> {
+        self.chunks(blob)?;
+        Ok(self.data[blob.offset..blob.offset + blob.len].to_vec())
+    }
+
+    /// Marks the chunks of `blob` as free again
+    ///
+    /// - waits for indefinitely for lock
+    /// - wakes up all allocations waiting for space, as the freed chunks might fit any of them
+    fn free(&mut self, blob: &Blob) -> Result<(), Error> {
+        let (start, count) = self.chunks(blob)?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        self.lock()?;
+        self.mark(start, count, false);
+        unsafe {
+            libc::pthread_mutex_unlock(&mut self.lock);
+        }
+        self.freed.notify_all();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "testing";
+
+    #[test]
+    fn setup() {
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::new(NAME, true).expect("Failed to setup Queue");
+
+        let request = Request {
+            operation: Operation::Insert.into(),
+            priority: 0,
+            key: 1,
+            val: 1,
+            counter: 0,
+        };
+
+        ipc_server
+            .request_put(&request)
+            .expect("Failed to put things into request buffer");
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn blobs() {
+        let ipc_server: ShmQueue<Blob, Blob> =
+            ShmQueue::new("testing-blobs", true).expect("Failed to setup Queue");
+        let ipc_client: ShmQueue<Blob, Blob> =
+            ShmQueue::new("testing-blobs", false).expect("Failed to connect to Queue");
+
+        let key = ipc_client
+            .blob_alloc(b"key")
+            .expect("Failed to allocate key");
+        let val = ipc_client
+            .blob_alloc(&[7; ARENA_CHUNK_SIZE * 3])
+            .expect("Failed to allocate value");
+        assert_ne!(key.offset, val.offset);
+
+        ipc_client
+            .request_put(&Request {
+                operation: Operation::Insert.into(),
+                priority: 0,
+                key,
+                val,
+                counter: 0,
+            })
+            .expect("Failed to put things into request buffer");
+
+        let request = ipc_server.request_get().expect("Failed to get request");
+        assert_eq!(ipc_server.blob_take(&request.key).unwrap(), b"key");
+        assert_eq!(
+            ipc_server.blob_take(&request.val).unwrap(),
+            vec![7; ARENA_CHUNK_SIZE * 3]
+        );
+
+        // Freed chunks are handed out again
+        let reused = ipc_server.blob_alloc(b"other").unwrap();
+        assert_eq!(reused.offset, key.offset);
+
+        // Blobs pointing outside of the arena are rejected
+        let invalid = Blob {
+            offset: ARENA_CHUNK_SIZE * ARENA_CHUNKS,
+            len: 1,
+        };
+        assert!(ipc_server.blob_read(&invalid).is_err());
+        assert!(ipc_server.blob_free(&invalid).is_err());
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn blob_alloc_timeout() {
+        let ipc_server: ShmQueue<Blob, Blob> =
+            ShmQueue::new("testing-blob-alloc-timeout", true).expect("Failed to setup Queue");
+        let ipc_client: ShmQueue<Blob, Blob> =
+            ShmQueue::new("testing-blob-alloc-timeout", false).expect("Failed to connect to Queue");
+
+        let all = ipc_client
+            .blob_alloc(&[1; ARENA_CHUNK_SIZE * ARENA_CHUNKS])
+            .expect("Failed to fill arena");
+        assert!(matches!(
+            ipc_server.blob_alloc_timeout(b"val", Duration::from_millis(10)),
+            Err(Error::ArenaFull)
+        ));
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(10));
+                ipc_client.blob_free(&all).unwrap();
+            });
+            ipc_server
+                .blob_alloc_timeout(b"val", Duration::from_secs(5))
+                .expect("Allocation was not woken up after space was freed");
+        });
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn queue_depth() {
+        let config = QueueConfig {
+            request_capacity: 3,
+            response_capacity: 5,
+            ..Default::default()
+        };
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::with_config("testing-depth", true, &config).expect("Failed to setup Queue");
+        let ipc_client: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-depth", false).expect("Failed to connect to Queue");
+        assert_eq!(ipc_client.request_capacity(), 3);
+        assert_eq!(ipc_client.response_capacity(), 5);
 
         let request = Request {
-            operation: Operation::Insert,
+            operation: Operation::Read.into(),
+            priority: 0,
             key: 1,
-            val: 1,
+            val: 0,
+            counter: 0,
+        };
+        for _ in 0..3 {
+            ipc_client.request_put(&request).unwrap();
+        }
+        assert!(matches!(
+            ipc_client.request_put(&request),
+            Err(Error::BufferFull)
+        ));
+        assert!(ipc_server.request_get().is_ok());
+        assert!(ipc_client.request_put(&request).is_ok());
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn put_blocking() {
+        let config = QueueConfig {
+            request_capacity: 1,
+            response_capacity: 1,
+            ..Default::default()
+        };
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::with_config("testing-blocking", true, &config)
+                .expect("Failed to setup Queue");
+        let ipc_client: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-blocking", false).expect("Failed to connect to Queue");
+
+        let request = |counter| Request {
+            operation: Operation::Read.into(),
+            priority: 0,
+            key: 1,
+            val: 0,
+            counter,
+        };
+        ipc_client.request_put_blocking(&request(0)).unwrap();
+        assert!(matches!(
+            ipc_client.request_put_timeout(&request(1), Duration::from_millis(10)),
+            Err(Error::BufferFull)
+        ));
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(10));
+                assert_eq!(ipc_server.request_get().unwrap().counter, 0);
+            });
+            ipc_client
+                .request_put_timeout(&request(1), Duration::from_secs(5))
+                .expect("Put was not woken up after space was freed");
+        });
+        assert_eq!(ipc_server.request_get().unwrap().counter, 1);
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn get_timeout() {
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-get-timeout", true).expect("Failed to setup Queue");
+        let ipc_client: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-get-timeout", false).expect("Failed to connect to Queue");
+
+        assert!(matches!(
+            ipc_client.response_try_get(),
+            Err(Error::BufferEmpty)
+        ));
+        assert!(matches!(
+            ipc_client.response_get_timeout(Duration::from_millis(10)),
+            Err(Error::Timeout)
+        ));
+
+        let response = Response {
+            operation: Operation::Read.into(),
+            status: Status::Ok.into(),
+            key: 1,
+            val: 2,
             counter: 0,
         };
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(10));
+                ipc_server.response_put(&response).unwrap();
+            });
+            // A deadline beyond the range of `time_t` must not wrap into the past
+            let response = ipc_client
+                .response_get_timeout(Duration::MAX)
+                .expect("Get was not woken up by new data");
+            assert_eq!(response.val, 2);
+        });
+        assert!(!Wait::timeout(Duration::MAX).expired());
+
+        ipc_server.response_put(&response).unwrap();
+        assert!(ipc_client.response_try_get().is_ok());
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn owner_died() {
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-owner-died", true).expect("Failed to setup Queue");
+
+        // Robust mutexes are also released when the owning thread exits
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let buffer = &ipc_server.buffer;
+                let Ring::Locked(ring) = &buffer.request_buffers[0] else {
+                    unreachable!()
+                };
+                let inner = ring.lock().unwrap();
+                inner.write_pos = 100;
+            });
+        });
 
+        let request = Request {
+            operation: Operation::Read.into(),
+            priority: 0,
+            key: 1,
+            val: 0,
+            counter: 0,
+        };
+        assert!(matches!(
+            ipc_server.request_put(&request),
+            Err(Error::OwnerDied)
+        ));
         ipc_server
             .request_put(&request)
-            .expect("Failed to put things into request buffer");
+            .expect("Lock was not recovered");
+        assert_eq!(ipc_server.request_try_get().unwrap().key, 1);
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn lock_free() {
+        let config = QueueConfig {
+            request_capacity: 4,
+            response_capacity: 4,
+            ring: RingKind::LockFree,
+            ..Default::default()
+        };
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::with_config("testing-lock-free", true, &config)
+                .expect("Failed to setup Queue");
+        let ipc_client: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-lock-free", false).expect("Failed to connect to Queue");
+        assert_eq!(ipc_client.ring_kind(), RingKind::LockFree);
+        assert_eq!(ipc_client.request_capacity(), 4);
+
+        let request = |counter| Request {
+            operation: Operation::Insert.into(),
+            priority: 0,
+            key: 1,
+            val: 2,
+            counter,
+        };
+        for counter in 0..4 {
+            ipc_client.request_put(&request(counter)).unwrap();
+        }
+        assert!(matches!(
+            ipc_client.request_put_timeout(&request(4), Duration::from_millis(10)),
+            Err(Error::BufferFull)
+        ));
+        for counter in 0..4 {
+            assert_eq!(ipc_server.request_try_get().unwrap().counter, counter);
+        }
+        assert!(matches!(
+            ipc_server.request_try_get(),
+            Err(Error::BufferEmpty)
+        ));
+        assert!(matches!(
+            ipc_server.request_get_timeout(Duration::from_millis(10)),
+            Err(Error::Timeout)
+        ));
+
+        // Every request from multiple producers is received exactly once by multiple consumers
+        const PER_PRODUCER: usize = 1000;
+        let received = std::sync::Mutex::new(vec![]);
+        std::thread::scope(|s| {
+            for producer in 0..3 {
+                let ipc_client = &ipc_client;
+                s.spawn(move || {
+                    for i in 0..PER_PRODUCER {
+                        ipc_client
+                            .request_put_blocking(&request(producer * PER_PRODUCER + i))
+                            .unwrap();
+                    }
+                });
+            }
+            for _ in 0..3 {
+                s.spawn(|| {
+                    while let Ok(request) =
+                        ipc_server.request_get_timeout(Duration::from_millis(200))
+                    {
+                        received.lock().unwrap().push(request.counter);
+                    }
+                });
+            }
+        });
+        let mut received = received.into_inner().unwrap();
+        received.sort();
+        assert_eq!(received, (0..3 * PER_PRODUCER).collect::<Vec<_>>());
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn drop_unlinks() {
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-drop", true).expect("Failed to setup Queue");
+        let ipc_client: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-drop", false).expect("Failed to connect to Queue");
+
+        // Dropping the client leaves the queue alone
+        drop(ipc_client);
+        assert!(ShmQueue::<u32, u32>::new("testing-drop", false).is_ok());
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+        ipc_server.stop().expect("second stop failed");
+        assert!(ShmQueue::<u32, u32>::new("testing-drop", false).is_err());
+        drop(ipc_server);
+
+        // Dropping the server without stop unlinks as well
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-drop", true).expect("Failed to setup Queue again");
+        drop(ipc_server);
+        assert!(ShmQueue::<u32, u32>::new("testing-drop", false).is_err());
+    }
+
+    #[test]
+    fn layout_mismatch() {
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-layout", true).expect("Failed to setup Queue");
+
+        assert!(ShmQueue::<u32, u32>::new("testing-layout", false).is_ok());
+
+        match ShmQueue::<u64, u32>::new("testing-layout", false) {
+            Err(Error::LayoutMismatch { field, .. }) => assert_eq!(field, "key size"),
+            _ => panic!("Client with a different key type was not rejected"),
+        }
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn stale_segment() {
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-stale", true).expect("Failed to setup Queue");
+
+        // The segment of a running server is not taken over
+        match ShmQueue::<u32, u32>::new("testing-stale", true) {
+            Err(Error::SegmentInUse(pid)) => assert_eq!(pid, std::process::id()),
+            _ => panic!("Segment of a running server was reclaimed"),
+        }
+
+        // Pretend the segment was left behind by a crashed server
+        let mut child = std::process::Command::new("true")
+            .spawn()
+            .expect("Failed to spawn process");
+        child.wait().unwrap();
+        unsafe {
+            (*ipc_server.buffer.header).owner.pid = child.id();
+        }
+        std::mem::forget(ipc_server);
+
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-stale", true).expect("Stale segment was not reclaimed");
+        assert!(ShmQueue::<u32, u32>::new("testing-stale", false).is_ok());
+
+        let config = QueueConfig {
+            force: true,
+            ..Default::default()
+        };
+        let forced: ShmQueue<u32, u32> = ShmQueue::with_config("testing-stale", true, &config)
+            .expect("Segment was not taken over by force");
+
+        // The server that lost its segment does not unlink the new one
+        drop(ipc_server);
+        assert!(ShmQueue::<u32, u32>::new("testing-stale", false).is_ok());
+        forced.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn close() {
+        for ring in [RingKind::Locked, RingKind::LockFree] {
+            let config = QueueConfig {
+                ring,
+                ..Default::default()
+            };
+            let ipc_server: ShmQueue<u32, u32> =
+                ShmQueue::with_config("testing-close", true, &config)
+                    .expect("Failed to setup Queue");
+            let ipc_client: ShmQueue<u32, u32> =
+                ShmQueue::new("testing-close", false).expect("Failed to connect to Queue");
+
+            let response = Response {
+                operation: Operation::Read.into(),
+                status: Status::Ok.into(),
+                key: 1,
+                val: 2,
+                counter: 0,
+            };
+
+            // Blocked readers on both sides are woken up
+            std::thread::scope(|s| {
+                let client = s.spawn(|| ipc_client.response_get());
+                let server = s.spawn(|| ipc_server.request_get());
+                std::thread::sleep(Duration::from_millis(10));
+                ipc_server.close().unwrap();
+                assert!(matches!(client.join().unwrap(), Err(Error::Closed)));
+                assert!(matches!(server.join().unwrap(), Err(Error::Closed)));
+            });
+
+            // Nothing can be added anymore
+            assert!(matches!(
+                ipc_server.response_put(&response),
+                Err(Error::Closed)
+            ));
+            assert!(matches!(ipc_client.response_try_get(), Err(Error::Closed)));
+
+            ipc_server.stop().expect("unlinking shared memory failed");
+        }
+    }
+
+    #[test]
+    fn close_drains() {
+        for ring in [RingKind::Locked, RingKind::LockFree] {
+            let config = QueueConfig {
+                ring,
+                ..Default::default()
+            };
+            let ipc_server: ShmQueue<u32, u32> =
+                ShmQueue::with_config("testing-close-drains", true, &config)
+                    .expect("Failed to setup Queue");
+
+            let response = Response {
+                operation: Operation::Read.into(),
+                status: Status::Ok.into(),
+                key: 1,
+                val: 2,
+                counter: 0,
+            };
+            ipc_server.response_put(&response).unwrap();
+            ipc_server.close().unwrap();
+            ipc_server.close().expect("second close failed");
+
+            // Entries queued before closing are still delivered
+            assert_eq!(ipc_server.response_get().unwrap().val, 2);
+            assert!(matches!(ipc_server.response_get(), Err(Error::Closed)));
+
+            ipc_server.stop().expect("unlinking shared memory failed");
+        }
+    }
+
+    #[test]
+    fn close_requests() {
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::new("testing-close-requests", true).expect("Failed to setup Queue");
+
+        let request = Request {
+            operation: Operation::Read.into(),
+            priority: 0,
+            key: 1,
+            val: 0,
+            counter: 0,
+        };
+        ipc_server.request_put(&request).unwrap();
+        ipc_server.close_requests().unwrap();
+        assert!(matches!(
+            ipc_server.request_put(&request),
+            Err(Error::Closed)
+        ));
+
+        // Queued requests can still be answered
+        let request = ipc_server.request_get().unwrap();
+        ipc_server
+            .response_put(&Response {
+                operation: request.operation,
+                status: Status::ShuttingDown.into(),
+                key: request.key,
+                val: 0,
+                counter: request.counter,
+            })
+            .expect("Response ring was closed as well");
+        assert!(matches!(ipc_server.request_get(), Err(Error::Closed)));
+        assert_eq!(
+            ipc_server.response_get().unwrap().status().unwrap(),
+            Status::ShuttingDown
+        );
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn batches() {
+        for ring in [RingKind::Locked, RingKind::LockFree] {
+            let config = QueueConfig {
+                request_capacity: 4,
+                response_capacity: 4,
+                ring,
+                ..Default::default()
+            };
+            let ipc_server: ShmQueue<u32, u32> =
+                ShmQueue::with_config("testing-batches", true, &config)
+                    .expect("Failed to setup Queue");
+            let ipc_client: ShmQueue<u32, u32> =
+                ShmQueue::new("testing-batches", false).expect("Failed to connect to Queue");
+
+            let requests: Vec<_> = (0..6)
+                .map(|counter| Request {
+                    operation: Operation::Insert.into(),
+                    priority: 0,
+                    key: 1,
+                    val: 2,
+                    counter,
+                })
+                .collect();
+
+            // Only as many as fit are written
+            assert_eq!(ipc_client.request_put_many(&requests).unwrap(), 4);
+            assert!(matches!(
+                ipc_client.request_put_many(&requests[4..]),
+                Err(Error::BufferFull)
+            ));
+            assert_eq!(ipc_client.request_put_many(&[]).unwrap(), 0);
+
+            let counters = |requests: Vec<Request<u32, u32>>| {
+                requests.iter().map(|r| r.counter).collect::<Vec<_>>()
+            };
+            assert_eq!(
+                counters(ipc_server.request_get_many(3).unwrap()),
+                vec![0, 1, 2]
+            );
+            assert_eq!(
+                ipc_client
+                    .request_put_many_timeout(&requests[4..], Duration::from_secs(5))
+                    .unwrap(),
+                2
+            );
+            assert_eq!(
+                counters(ipc_server.request_get_many(10).unwrap()),
+                vec![3, 4, 5]
+            );
+            assert!(matches!(
+                ipc_server.request_try_get_many(10),
+                Err(Error::BufferEmpty)
+            ));
+            assert!(matches!(
+                ipc_server.request_get_many_timeout(10, Duration::from_millis(10)),
+                Err(Error::Timeout)
+            ));
+
+            // A blocked batch get is woken up by a batch put
+            std::thread::scope(|s| {
+                let server = s.spawn(|| ipc_server.request_get_many(10));
+                std::thread::sleep(Duration::from_millis(10));
+                ipc_client
+                    .request_put_many_blocking(&requests[..2])
+                    .unwrap();
+                assert!(!server.join().unwrap().unwrap().is_empty());
+            });
+
+            ipc_server.stop().expect("unlinking shared memory failed");
+        }
+    }
+
+    #[test]
+    fn reserve_peek() {
+        for ring in [RingKind::Locked, RingKind::LockFree] {
+            let config = QueueConfig {
+                request_capacity: 2,
+                response_capacity: 2,
+                ring,
+                ..Default::default()
+            };
+            let ipc_server: ShmQueue<u32, u32> =
+                ShmQueue::with_config("testing-reserve", true, &config)
+                    .expect("Failed to setup Queue");
+            let ipc_client: ShmQueue<u32, u32> =
+                ShmQueue::new("testing-reserve", false).expect("Failed to connect to Queue");
+
+            let mut slot = ipc_client.request_reserve().unwrap();
+            slot.operation = Operation::Insert.into();
+            slot.key = 1;
+            slot.val = 2;
+            slot.counter = 0;
+            slot.commit();
+
+            // A dropped reservation does not publish anything
+            let mut slot = ipc_client.request_reserve().unwrap();
+            slot.counter = 1;
+            drop(slot);
+
+            let request = ipc_server.request_peek().unwrap();
+            assert_eq!((request.key, request.val, request.counter), (1, 2, 0));
+            request.release();
+            assert!(matches!(
+                ipc_server.request_try_peek(),
+                Err(Error::BufferEmpty)
+            ));
+
+            // Released slots can be reserved again
+            drop(ipc_client.request_reserve().unwrap());
+            let mut slot = ipc_client.request_reserve_blocking().unwrap();
+            slot.counter = 4;
+            slot.commit();
+            assert_eq!(ipc_server.request_get().unwrap().counter, 4);
+
+            ipc_server.stop().expect("unlinking shared memory failed");
+        }
+    }
+
+    #[test]
+    fn reserve_skip() {
+        let config = QueueConfig {
+            ring: RingKind::LockFree,
+            ..Default::default()
+        };
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::with_config("testing-reserve-skip", true, &config)
+                .expect("Failed to setup Queue");
+
+        // The lock-free ring hands out slots concurrently, so a later slot can be published first
+        let first = ipc_server.response_reserve().unwrap();
+        let mut second = ipc_server.response_reserve().unwrap();
+        second.counter = 1;
+        second.commit();
+        assert!(matches!(
+            ipc_server.response_try_get(),
+            Err(Error::BufferEmpty)
+        ));
+
+        // Dropping the first one lets readers skip over it
+        drop(first);
+        assert_eq!(ipc_server.response_try_get().unwrap().counter, 1);
+        assert!(matches!(
+            ipc_server.response_try_get(),
+            Err(Error::BufferEmpty)
+        ));
+
+        // The skipped slot was freed again
+        for counter in 0..DEFAULT_QUEUE_DEPTH {
+            let mut slot = ipc_server.response_reserve().unwrap();
+            slot.counter = counter;
+            slot.commit();
+        }
 
         ipc_server.stop().expect("unlinking shared memory failed");
     }
+
+    #[test]
+    fn control() {
+        let server = Control::create("testing-control", 1, &QueueConfig::default())
+            .expect("Failed to setup control");
+        let client = Control::open("testing-control").expect("Failed to connect to control");
+        let timeout = Duration::from_secs(5);
+
+        std::thread::scope(|s| {
+            let registered = s.spawn(|| {
+                let registration = client.register(timeout).unwrap();
+                assert_eq!(registration.queue_name(), "testing-control-0");
+                // There is only one slot
+                assert!(matches!(
+                    client.register(Duration::ZERO),
+                    Err(Error::NoFreeSlot)
+                ));
+            });
+
+            assert_eq!(server.poll(timeout).unwrap(), [ControlEvent::Connect(0)]);
+            server.accept(0).unwrap();
+            registered.join().unwrap();
+        });
+
+        // Dropping the registration releases the slot
+        assert_eq!(server.poll(timeout).unwrap(), [ControlEvent::Disconnect(0)]);
+        server.free(0).unwrap();
+        assert!(server.poll(Duration::ZERO).unwrap().is_empty());
+
+        // A client giving up has to be cleaned up like a disconnected one
+        assert!(matches!(
+            client.register(Duration::from_millis(10)),
+            Err(Error::Timeout)
+        ));
+        assert_eq!(server.poll(timeout).unwrap(), [ControlEvent::Disconnect(0)]);
+        server.accept(0).unwrap();
+        server.free(0).unwrap();
+
+        server.close().unwrap();
+        assert!(matches!(client.register(timeout), Err(Error::Closed)));
+        assert!(matches!(server.poll(timeout), Err(Error::Closed)));
+    }
+
+    #[test]
+    fn control_reclaim() {
+        // With a single slot the control segment is smaller than the header of a queue
+        let config = QueueConfig::default();
+        let server = Control::create("testing-control-reclaim", 1, &config)
+            .expect("Failed to setup control");
+        std::mem::forget(server);
+
+        match Control::create("testing-control-reclaim", 1, &config) {
+            Err(Error::SegmentInUse(pid)) => assert_eq!(pid, std::process::id()),
+            _ => panic!("Control segment of a running server was reclaimed"),
+        }
+
+        // Pretend the control segment was left behind by a crashed server
+        let mut child = std::process::Command::new("true")
+            .spawn()
+            .expect("Failed to spawn process");
+        child.wait().unwrap();
+        let fd = shm::open(
+            "testing-control-reclaim-control",
+            shm::OFlags::RDWR,
+            Mode::empty(),
+        )
+        .unwrap();
+        rustix::io::pwrite(
+            &fd,
+            &child.id().to_ne_bytes(),
+            std::mem::offset_of!(SegmentOwner, pid) as u64,
+        )
+        .unwrap();
+
+        Control::create("testing-control-reclaim", 1, &config)
+            .expect("Stale control segment was not reclaimed");
+    }
+
+    #[test]
+    fn control_reject() {
+        let server = Control::create("testing-control-reject", 2, &QueueConfig::default())
+            .expect("Failed to setup control");
+        let client = Control::open("testing-control-reject").expect("Failed to connect to control");
+        let timeout = Duration::from_secs(5);
+
+        std::thread::scope(|s| {
+            let registered = s.spawn(|| {
+                assert!(matches!(client.register(timeout), Err(Error::Rejected)));
+            });
+
+            assert_eq!(server.poll(timeout).unwrap(), [ControlEvent::Connect(0)]);
+            server.reject(0).unwrap();
+            registered.join().unwrap();
+        });
+
+        // The client freed the slot again
+        assert!(server.poll(Duration::ZERO).unwrap().is_empty());
+        assert!(matches!(server.accept(2), Err(Error::UnknownSlot(2))));
+    }
+
+    #[test]
+    fn permissions() {
+        let config = QueueConfig {
+            mode: 0o640,
+            group: Some(unsafe { libc::getgid() }),
+            ..Default::default()
+        };
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::with_config("testing-permissions", true, &config)
+                .expect("Failed to setup Queue");
+        // Not reduced by the umask
+        assert_eq!(fstat(&ipc_server).unwrap().st_mode & 0o777, 0o640);
+
+        assert!(matches!(
+            Control::create("testing/permissions", 1, &config),
+            Err(Error::InvalidPrefix(_))
+        ));
+        assert!(matches!(Control::open(""), Err(Error::InvalidPrefix(_))));
+
+        ipc_server.stop().expect("unlinking shared memory failed");
+    }
+
+    #[test]
+    fn anonymous() {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let credentials = peer_credentials(&server_stream).unwrap();
+        assert_eq!(credentials.pid, std::process::id());
+        assert_eq!(credentials.uid, unsafe { libc::getuid() });
+
+        let config = QueueConfig {
+            request_capacity: 4,
+            ..Default::default()
+        };
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::anonymous(&config).expect("Failed to setup Queue");
+        ipc_server.send_to(&server_stream).unwrap();
+        let ipc_client: ShmQueue<u32, u32> =
+            ShmQueue::receive_from(&client_stream).expect("Failed to receive Queue");
+        assert_eq!(ipc_client.request_capacity(), 4);
+
+        ipc_client
+            .request_put(&Request::new(Operation::Insert, 1, 2, 0))
+            .unwrap();
+        assert_eq!(ipc_server.request_get().unwrap().val, 2);
+
+        // The size is sealed
+        assert!(ftruncate(&ipc_client, 0).is_err());
+
+        // A server closing the connection without sending a queue
+        drop(server_stream);
+        assert!(matches!(
+            ShmQueue::<u32, u32>::receive_from(&client_stream),
+            Err(Error::Rejected)
+        ));
+    }
+
+    #[test]
+    fn event_fd() {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let config = QueueConfig {
+            event_fd: true,
+            ..Default::default()
+        };
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::anonymous(&config).expect("Failed to setup Queue");
+        ipc_server.send_to(&server_stream).unwrap();
+        let ipc_client: ShmQueue<u32, u32> =
+            ShmQueue::receive_from(&client_stream).expect("Failed to receive Queue");
+
+        let event = ipc_client.event_fd().expect("No eventfd was handed over");
+        let readable = || {
+            let mut fds = [rustix::event::PollFd::new(
+                &event,
+                rustix::event::PollFlags::IN,
+            )];
+            rustix::event::poll(&mut fds, 0).unwrap() == 1
+        };
+        assert!(!readable());
+
+        let response = Response::new(Operation::Read, Status::Ok, 1, 2, 0);
+        ipc_server.response_put(&response).unwrap();
+        assert!(readable());
+        ipc_client.clear_event().unwrap();
+        assert!(!readable());
+        assert_eq!(ipc_client.response_try_get().unwrap().val, 2);
+
+        // Responses written in place signal once they are committed
+        let mut reservation = ipc_server.response_reserve().unwrap();
+        *reservation = response;
+        assert!(!readable());
+        reservation.commit();
+        assert!(readable());
+        ipc_client.clear_event().unwrap();
+        ipc_client.response_try_get().unwrap();
+
+        ipc_server.close().unwrap();
+        assert!(readable());
+
+        // Without the option only the memory is handed over
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::anonymous(&QueueConfig::default()).expect("Failed to setup Queue");
+        ipc_server.send_to(&server_stream).unwrap();
+        let ipc_client: ShmQueue<u32, u32> =
+            ShmQueue::receive_from(&client_stream).expect("Failed to receive Queue");
+        assert!(ipc_client.event_fd().is_none());
+        ipc_client.clear_event().unwrap();
+    }
+
+    #[test]
+    fn heartbeat() {
+        let ipc_server: ShmQueue<u32, u32> =
+            ShmQueue::anonymous(&QueueConfig::default()).expect("Failed to setup Queue");
+        let timeout = Duration::from_millis(50);
+
+        // No client connected yet
+        ipc_server.check_peer(timeout).unwrap();
+
+        let ipc_client: ShmQueue<u32, u32> =
+            ShmQueue::from_fd(ipc_server.fd.try_clone().unwrap()).expect("Failed to open Queue");
+        ipc_server.check_peer(timeout).unwrap();
+        ipc_client.check_peer(timeout).unwrap();
+
+        // Only the side that keeps beating stays alive
+        std::thread::sleep(timeout * 2);
+        ipc_client.heartbeat();
+        ipc_server.check_peer(timeout).unwrap();
+        match ipc_client.check_peer(timeout) {
+            Err(Error::PeerTimeout(pid)) => assert_eq!(pid, std::process::id()),
+            _ => panic!("Missing heartbeats were not detected"),
+        }
+        ipc_server.heartbeat();
+        ipc_client.check_peer(timeout).unwrap();
+
+        // Pretend the client crashed
+        let mut child = std::process::Command::new("true")
+            .spawn()
+            .expect("Failed to spawn process");
+        child.wait().unwrap();
+        unsafe { &*ipc_server.buffer.header }.client.claim(Process {
+            pid: child.id(),
+            start_time: 0,
+        });
+        match ipc_server.check_peer(timeout) {
+            Err(Error::PeerDied(pid)) => assert_eq!(pid, child.id()),
+            _ => panic!("Dead client was not detected"),
+        }
+    }
+
+    #[test]
+    fn lanes() {
+        for ring in [RingKind::Locked, RingKind::LockFree] {
+            let config = QueueConfig {
+                request_capacity: 8,
+                lanes: 3,
+                ring,
+                ..Default::default()
+            };
+            let ipc_server: ShmQueue<u32, u32> =
+                ShmQueue::anonymous(&config).expect("Failed to setup Queue");
+            let ipc_client: ShmQueue<u32, u32> =
+                ShmQueue::from_fd(ipc_server.fd.try_clone().unwrap())
+                    .expect("Failed to open Queue");
+            assert_eq!(ipc_client.lanes(), 3);
+            assert_eq!(ipc_client.request_capacity(), 8);
+
+            let request = |counter, priority| {
+                Request::new(Operation::Read, 1, 0, counter).with_priority(priority)
+            };
+
+            // Batches stop at the first request of another lane
+            let low = [request(0, 0), request(1, 0), request(100, 2)];
+            assert_eq!(ipc_client.request_put_many(&low).unwrap(), 2);
+            // Priorities beyond the last lane end up in the last one
+            let high: Vec<_> = (100..108).map(|counter| request(counter, 9)).collect();
+            assert_eq!(ipc_client.request_put_many(&high).unwrap(), 8);
+            assert!(matches!(
+                ipc_client.request_put(&request(108, 2)),
+                Err(Error::BufferFull)
+            ));
+
+            // The low lane is served after it was passed over `STARVATION_LIMIT` times
+            let counters: Vec<_> = (0..10)
+                .map(|_| ipc_server.request_try_get().unwrap().counter)
+                .collect();
+            assert_eq!(counters, [100, 101, 102, 103, 0, 104, 105, 106, 107, 1]);
+            assert!(matches!(
+                ipc_server.request_try_get(),
+                Err(Error::BufferEmpty)
+            ));
+            assert!(matches!(
+                ipc_server.request_get_timeout(Duration::from_millis(10)),
+                Err(Error::Timeout)
+            ));
+
+            // Waiting readers are woken up by a request in any lane
+            std::thread::scope(|s| {
+                let reader = s.spawn(|| ipc_server.request_get_many(4).unwrap());
+                std::thread::sleep(Duration::from_millis(20));
+                ipc_client.request_put(&request(50, 1)).unwrap();
+                assert_eq!(reader.join().unwrap()[0].counter, 50);
+            });
+
+            ipc_client.request_put(&request(2, 0)).unwrap();
+            ipc_client.request_put(&request(51, 1)).unwrap();
+            assert_eq!(ipc_server.request_try_peek().unwrap().counter, 51);
+            ipc_server.close_requests().unwrap();
+            assert_eq!(ipc_server.request_get().unwrap().counter, 2);
+            assert!(matches!(ipc_server.request_get(), Err(Error::Closed)));
+        }
+
+        let config = QueueConfig {
+            lanes: MAX_LANES + 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            ShmQueue::<u32, u32>::anonymous(&config),
+            Err(Error::InvalidLanes)
+        ));
+    }
+
+    /// Xorshift generator, good enough to produce garbage for the fuzz tests
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn fill(&mut self, bytes: &mut [u8]) {
+            for chunk in bytes.chunks_mut(8) {
+                let len = chunk.len();
+                chunk.copy_from_slice(&self.next().to_ne_bytes()[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_fuzz() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let values = (0..8)
+            .chain([u32::MAX])
+            .chain((0..10_000).map(|_| rng.next() as u32));
+
+        for raw in values {
+            match Operation::try_from(raw) {
+                Ok(operation) => assert_eq!(u32::from(operation), raw),
+                Err(e) => assert!(matches!(e, Error::InvalidOperation(v) if v == raw)),
+            }
+            match Status::try_from(raw) {
+                Ok(status) => assert_eq!(u32::from(status), raw),
+                Err(e) => assert!(matches!(e, Error::InvalidStatus(v) if v == raw)),
+            }
+        }
+        assert!(Operation::try_from(3).is_err());
+        assert!(Status::try_from(9).is_err());
+    }
+
+    #[test]
+    fn slot_fuzz() {
+        for ring in [RingKind::Locked, RingKind::LockFree] {
+            let config = QueueConfig {
+                ring,
+                ..Default::default()
+            };
+            let ipc_server: ShmQueue<Blob, Blob> =
+                ShmQueue::with_config("testing-slot-fuzz", true, &config)
+                    .expect("Failed to setup Queue");
+            let ipc_client: ShmQueue<Blob, Blob> =
+                ShmQueue::new("testing-slot-fuzz", false).expect("Failed to connect to Queue");
+            ipc_client.blob_alloc(b"occupied").unwrap();
+
+            let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+            for _ in 0..1000 {
+                // Whatever a client writes into the slot, decoding it on the server must not go wrong
+                let mut slot = ipc_client.request_reserve().unwrap();
+                let bytes = unsafe {
+                    std::slice::from_raw_parts_mut(
+                        &mut *slot as *mut Request<Blob, Blob> as *mut u8,
+                        size_of::<Request<Blob, Blob>>(),
+                    )
+                };
+                rng.fill(bytes);
+                // Keep some blobs inside the arena, so not only the bounds check is hit
+                if rng.next() % 2 == 0 {
+                    slot.key.offset %= 2 * ARENA_CHUNK_SIZE;
+                    slot.key.len %= 2 * ARENA_CHUNK_SIZE;
+                }
+                slot.commit();
+
+                let request = ipc_server.request_get().unwrap();
+                assert_eq!(request.operation().is_ok(), request.operation < 3);
+                for blob in [request.key, request.val] {
+                    if let Ok(data) = ipc_server.blob_read(&blob) {
+                        assert_eq!(data.len(), blob.len);
+                    }
+                }
+            }
+
+            ipc_server.stop().expect("unlinking shared memory failed");
+        }
+    }
 }
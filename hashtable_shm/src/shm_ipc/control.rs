@@ -0,0 +1,531 @@
+use super::futex::{AdaptiveSpin, Notifier};
+use super::process::Process;
+use super::{
+    align_up, reclaim, set_permissions, setup_lock, Error, QueueConfig, SegmentOwner, Wait,
+    HEARTBEAT_INTERVAL, PROTOCOL_VERSION,
+};
+use rustix::fs::{fstat, ftruncate, Mode};
+use rustix::mm::{mmap, munmap, MapFlags, ProtFlags};
+use rustix::shm;
+use std::mem::{align_of, size_of};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Identifies a control segment ("HTSHMC" followed by two zero bytes)
+const CONTROL_MAGIC: u64 = u64::from_be_bytes(*b"HTSHMC\0\0");
+
+// States of a client slot. They are written by both sides, so they are kept as plain integers.
+/// Nobody uses the slot
+const FREE: u32 = 0;
+/// A client claimed the slot and waits for the server to set up its queue
+const REQUESTED: u32 = 1;
+/// The queue of the slot exists and the client uses it
+const READY: u32 = 2;
+/// The server could not set up a queue, the client frees the slot again
+const REJECTED: u32 = 3;
+/// The client is gone, the server tears down the queue and frees the slot
+const RELEASED: u32 = 4;
+
+/// Well-known segment through which clients get a queue assigned by the server
+///
+/// The server creates `<prefix>-control` with a fixed number of client slots. A client claims a free slot
+/// with `register`, the server creates the queue `<prefix>-<id>` for it and tears it down again once
+/// the client released the slot or died.
+///
+/// Changes are announced through a futex instead of a condition variable: a process killed while waiting
+/// on a process-shared condition variable can leave it in a state that blocks everybody using it afterwards.
+///
+/// Dropping the control segment on the server side destroys its lock and unlinks the name,
+/// so clients must not use it anymore afterwards.
+pub struct Control {
+    ptr: *mut ControlHeader,
+    slots: *mut ClientSlot,
+    size: usize,
+    spin: AdaptiveSpin,
+    prefix: String,
+    server: bool,
+    /// Inode of the segment, to tell if the name still refers to it
+    inode: u64,
+    unlinked: AtomicBool,
+}
+
+#[repr(C)]
+/// Start of the control segment, followed by the client slots
+///
+/// Begins with the same `SegmentOwner` as a queue segment, so stale control segments are reclaimed the same way.
+struct ControlHeader {
+    owner: SegmentOwner,
+    slot_count: u32,
+    /// Non-zero once the server closed the segment, not a `bool` as the client could write any value
+    closed: u32,
+    segment_size: u64,
+    lock: libc::pthread_mutex_t,
+    /// Notified on every change of a slot, both sides wait on it
+    changed: Notifier,
+}
+
+#[repr(C)]
+/// Entry of the client table, protected by the lock in the header
+struct ClientSlot {
+    state: u32,
+    /// Client that claimed the slot
+    pid: u32,
+    start_time: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+/// Change of the client table the server has to act on, see `Control::poll`
+pub enum ControlEvent {
+    /// A client waits for its queue, answer with `Control::accept` or `Control::reject`
+    Connect(usize),
+    /// A client released its slot or died, answer with `Control::free` after tearing down its queue
+    Disconnect(usize),
+}
+
+/// Slot claimed by a client, released again when dropped
+pub struct Registration<'a> {
+    control: &'a Control,
+    id: usize,
+}
+
+// The header is only accessed through its lock and the atomic magic
+unsafe impl Send for Control {}
+unsafe impl Sync for Control {}
+
+impl Control {
+    /// Name of the control segment for `prefix`
+    fn name(prefix: &str) -> Result<String, Error> {
+        // Names of shared memory objects are a single path component
+        match prefix.is_empty() || prefix.contains('/') {
+            true => Err(Error::InvalidPrefix(prefix.to_string())),
+            false => Ok(format!("{}-control", prefix)),
+        }
+    }
+
+    /// Offset of the first slot from the start of the segment
+    fn slots_offset() -> usize {
+        align_up(size_of::<ControlHeader>(), align_of::<ClientSlot>())
+    }
+
+    /// Size of a control segment with `slot_count` slots
+    fn size(slot_count: usize) -> usize {
+        Self::slots_offset() + slot_count * size_of::<ClientSlot>()
+    }
+
+    /// Creates the control segment for `prefix` with room for `slot_count` clients at once
+    ///
+    /// A segment left behind by a server that is no longer running is taken over, `config.force` also takes over
+    /// the segment of a running server. The segment gets the mode and group of `config` like the queues.
+    pub fn create(prefix: &str, slot_count: usize, config: &QueueConfig) -> Result<Self, Error> {
+        let slot_count = match slot_count {
+            1..=0xffff_ffff => slot_count as u32,
+            _ => return Err(Error::InvalidCapacity),
+        };
+        let name = Self::name(prefix)?;
+        let flags = shm::OFlags::CREATE | shm::OFlags::EXCL | shm::OFlags::RDWR;
+        let mode = Mode::RUSR | Mode::WUSR;
+        let fd = match shm::open(&name, flags, mode) {
+            Err(rustix::io::Errno::EXIST) => {
+                reclaim(&name, config.force)?;
+                shm::open(&name, flags, mode)?
+            }
+            fd => fd?,
+        };
+
+        let inode = fstat(&fd)?.st_ino;
+        let size = Self::size(slot_count as usize);
+        if let Err(e) = set_permissions(&fd, config).and_then(|_| Ok(ftruncate(&fd, size as u64)?))
+        {
+            // Report why the setup failed, not whether the cleanup did
+            let _ = shm::unlink(&name);
+            return Err(e);
+        }
+
+        let ptr = unsafe {
+            mmap(
+                null_mut(),
+                size,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED,
+                &fd,
+                0,
+            )?
+        } as *mut u8;
+        // Only becomes the server side once the lock is set up, so a failed setup just unmaps again
+        let mut control = unsafe { Self::new(ptr, size, prefix, false, inode) };
+
+        // The segment is zeroed by ftruncate, so every slot starts out free
+        let header = unsafe { &mut *control.ptr };
+        header.owner.claim(Process::current());
+        if let Err(e) = setup_lock(&mut header.lock) {
+            // Report why the setup failed, not whether the cleanup did
+            let _ = shm::unlink(&name);
+            return Err(e);
+        }
+        header.changed.init();
+        header.slot_count = slot_count;
+        header.closed = 0;
+        header.segment_size = size as u64;
+        header.owner.magic.store(CONTROL_MAGIC, Ordering::Release);
+        control.server = true;
+
+        Ok(control)
+    }
+
+    /// Connects to the control segment for `prefix` created by the server
+    pub fn open(prefix: &str) -> Result<Self, Error> {
+        let fd = shm::open(Self::name(prefix)?, shm::OFlags::RDWR, Mode::empty())?;
+        let inode = fstat(&fd)?.st_ino;
+
+        // We can only look at the header if the segment is large enough to contain one
+        let size = fstat(&fd)?.st_size as usize;
+        if size < size_of::<ControlHeader>() {
+            return Err(Error::LayoutMismatch {
+                field: "segment size",
+                expected: size_of::<ControlHeader>() as u64,
+                found: size as u64,
+            });
+        }
+
+        let ptr = unsafe {
+            mmap(
+                null_mut(),
+                size,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED,
+                &fd,
+                0,
+            )?
+        } as *mut u8;
+        // Dropping the handle unmaps the segment again if it does not match
+        let control = unsafe { Self::new(ptr, size, prefix, false, inode) };
+        control.validate()?;
+
+        Ok(control)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to a mapping of `size` bytes, which is released when the handle is dropped
+    unsafe fn new(ptr: *mut u8, size: usize, prefix: &str, server: bool, inode: u64) -> Self {
+        Self {
+            ptr: ptr as *mut ControlHeader,
+            slots: ptr.add(Self::slots_offset()) as *mut ClientSlot,
+            size,
+            spin: AdaptiveSpin::new(),
+            prefix: prefix.to_string(),
+            server,
+            inode,
+            unlinked: AtomicBool::new(false),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let header = unsafe { &*self.ptr };
+        let check = |field: &'static str, expected: u64, found: u64| match expected == found {
+            true => Ok(()),
+            false => Err(Error::LayoutMismatch {
+                field,
+                expected,
+                found,
+            }),
+        };
+
+        check(
+            "magic",
+            CONTROL_MAGIC,
+            header.owner.magic.load(Ordering::Acquire),
+        )?;
+        check(
+            "protocol version",
+            PROTOCOL_VERSION as u64,
+            header.owner.version as u64,
+        )?;
+        check(
+            "segment size",
+            Self::size(header.slot_count as usize) as u64,
+            header.segment_size,
+        )?;
+        check("segment size", header.segment_size, self.size as u64)
+    }
+
+    /// Number of clients that can be registered at once
+    pub fn slot_count(&self) -> usize {
+        unsafe { &*self.ptr }.slot_count as usize
+    }
+
+    /// Name of the queue of the client in slot `id`
+    pub fn queue_name(&self, id: usize) -> String {
+        format!("{}-{}", self.prefix, id)
+    }
+
+    /// Claims a free slot and waits at most `timeout` until the server set up its queue
+    ///
+    /// - returns `Error::NoFreeSlot` if all slots are in use and `Error::Rejected` if the server refused the client
+    /// - returns `Error::Closed` if the server shut down and `Error::PeerDied` if it died
+    pub fn register(&self, timeout: Duration) -> Result<Registration<'_>, Error> {
+        let deadline = Instant::now() + timeout;
+        let header = self.lock()?;
+        if header.closed != 0 {
+            self.unlock(header);
+            return Err(Error::Closed);
+        }
+
+        let Some(id) = (0..self.slot_count()).find(|&id| self.slot(id).state == FREE) else {
+            self.unlock(header);
+            return Err(Error::NoFreeSlot);
+        };
+        let owner = Process::current();
+        let slot = self.slot(id);
+        slot.pid = owner.pid;
+        slot.start_time = owner.start_time;
+        slot.state = REQUESTED;
+        self.unlock(header);
+        self.changed().notify_all();
+
+        // Waits in steps, so we notice if the server died instead of waiting for the whole timeout
+        let res = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let wait = Wait::timeout(remaining.min(HEARTBEAT_INTERVAL));
+            let res = self.changed().wait_until(&wait, &self.spin, || {
+                let header = match self.lock() {
+                    Ok(header) => header,
+                    Err(e) => return Some(Err(e)),
+                };
+                let res = match (header.closed != 0, slot.state) {
+                    (_, READY) => Some(Ok(())),
+                    (_, REJECTED) => {
+                        slot.state = FREE;
+                        Some(Err(Error::Rejected))
+                    }
+                    (true, _) => {
+                        slot.state = FREE;
+                        Some(Err(Error::Closed))
+                    }
+                    _ => None,
+                };
+                self.unlock(header);
+                res
+            });
+            if let Some(res) = res {
+                break Ok(res);
+            }
+            if remaining <= HEARTBEAT_INTERVAL {
+                break Err(Error::Timeout);
+            }
+            let owner = self.owner();
+            if !owner.is_alive() {
+                break Err(Error::PeerDied(owner.pid));
+            }
+        };
+
+        match res {
+            Ok(res) => res.map(|_| Registration { control: self, id }),
+            Err(e) => {
+                // The server might be setting up the queue right now, so it has to tear it down again
+                let header = self.lock()?;
+                slot.state = match slot.state {
+                    REJECTED => FREE,
+                    _ => RELEASED,
+                };
+                self.unlock(header);
+                self.changed().notify_all();
+                Err(e)
+            }
+        }
+    }
+
+    /// Waits at most `timeout` for changes of the client table
+    ///
+    /// Clients that died are reported as disconnected. Every event is reported again until it was answered,
+    /// so each one has to be answered before polling again.
+    /// returns `Error::Closed` once the control segment was closed
+    pub fn poll(&self, timeout: Duration) -> Result<Vec<ControlEvent>, Error> {
+        let wait = Wait::timeout(timeout);
+
+        let mut checked = false;
+        let res = self.changed().wait_until(&wait, &self.spin, || {
+            let header = match self.lock() {
+                Ok(header) => header,
+                Err(e) => return Some(Err(e)),
+            };
+            if header.closed != 0 {
+                self.unlock(header);
+                return Some(Err(Error::Closed));
+            }
+
+            let mut events = vec![];
+            for id in 0..self.slot_count() {
+                let slot = self.slot(id);
+                let owner = Process {
+                    pid: slot.pid,
+                    start_time: slot.start_time,
+                };
+                match slot.state {
+                    // Looking at /proc for every slot is too expensive to do on every change
+                    REQUESTED | READY if !checked && !owner.is_alive() => {
+                        slot.state = RELEASED;
+                        events.push(ControlEvent::Disconnect(id));
+                    }
+                    REQUESTED => events.push(ControlEvent::Connect(id)),
+                    RELEASED => events.push(ControlEvent::Disconnect(id)),
+                    _ => (),
+                }
+            }
+            checked = true;
+            self.unlock(header);
+
+            match events.is_empty() {
+                true => None,
+                false => Some(Ok(events)),
+            }
+        });
+
+        res.unwrap_or(Ok(vec![]))
+    }
+
+    /// Tells the client in slot `id` that its queue is ready
+    ///
+    /// If the client gave up in the meantime, the slot is reported as disconnected by the next `poll`
+    pub fn accept(&self, id: usize) -> Result<(), Error> {
+        self.transition(id, REQUESTED, READY)
+    }
+
+    /// Tells the client in slot `id` that no queue could be set up for it
+    pub fn reject(&self, id: usize) -> Result<(), Error> {
+        self.transition(id, REQUESTED, REJECTED)?;
+        // Nobody is waiting for the answer anymore
+        self.transition(id, RELEASED, FREE)
+    }
+
+    /// Frees slot `id` after the queue of the disconnected client was torn down
+    pub fn free(&self, id: usize) -> Result<(), Error> {
+        self.transition(id, RELEASED, FREE)
+    }
+
+    /// Marks the segment as closed, so waiting clients and `poll` return `Error::Closed`
+    ///
+    /// Can safely be called more than once
+    pub fn close(&self) -> Result<(), Error> {
+        let header = self.lock()?;
+        header.closed = 1;
+        self.unlock(header);
+        self.changed().notify_all();
+        Ok(())
+    }
+
+    /// Removes the name of the control segment, so no new client can connect
+    ///
+    /// Only has an effect on the server side and can safely be called more than once.
+    pub fn stop(&self) -> Result<(), Error> {
+        let name = Self::name(&self.prefix)?;
+        if self.server && !self.unlinked.swap(true, Ordering::AcqRel) {
+            let current =
+                shm::open(&name, shm::OFlags::RDONLY, Mode::empty()).and_then(|fd| fstat(&fd));
+            match current {
+                Ok(stat) if stat.st_ino == self.inode => match shm::unlink(&name) {
+                    Ok(()) | Err(rustix::io::Errno::NOENT) => (),
+                    Err(e) => return Err(e.into()),
+                },
+                Ok(_) | Err(rustix::io::Errno::NOENT) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves slot `id` from state `from` to `to`, does nothing if it is in another state
+    fn transition(&self, id: usize, from: u32, to: u32) -> Result<(), Error> {
+        if id >= self.slot_count() {
+            return Err(Error::UnknownSlot(id));
+        }
+
+        let header = self.lock()?;
+        let slot = self.slot(id);
+        let changed = slot.state == from;
+        if changed {
+            slot.state = to;
+        }
+        self.unlock(header);
+        if changed {
+            self.changed().notify_all();
+        }
+
+        Ok(())
+    }
+
+    /// Server that created the segment
+    fn owner(&self) -> Process {
+        unsafe { &*self.ptr }.owner.process()
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn slot(&self, id: usize) -> &mut ClientSlot {
+        unsafe { &mut *self.slots.add(id) }
+    }
+
+    fn changed(&self) -> &Notifier {
+        unsafe { &(*self.ptr).changed }
+    }
+
+    /// Acquires the lock of the table
+    ///
+    /// Every change is a single store to the state of a slot, so if the previous owner died
+    /// the table is still consistent and we just continue.
+    #[allow(clippy::mut_from_ref)]
+    fn lock(&self) -> Result<&mut ControlHeader, Error> {
+        let header = unsafe { &mut *self.ptr };
+        match unsafe { libc::pthread_mutex_lock(&mut header.lock) } {
+            0 => Ok(header),
+            libc::EOWNERDEAD => {
+                unsafe {
+                    libc::pthread_mutex_consistent(&mut header.lock);
+                }
+                Ok(header)
+            }
+            rc => Err(rustix::io::Errno::from_raw_os_error(rc).into()),
+        }
+    }
+
+    fn unlock(&self, header: &mut ControlHeader) {
+        unsafe {
+            libc::pthread_mutex_unlock(&mut header.lock);
+        }
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        if self.server {
+            let _ = self.stop();
+            let _ = self.close();
+            let header = unsafe { &mut *self.ptr };
+            unsafe {
+                libc::pthread_mutex_destroy(&mut header.lock);
+            }
+        }
+        unsafe {
+            let _ = munmap(self.ptr as *mut _, self.size);
+        }
+    }
+}
+
+impl Registration<'_> {
+    /// Slot assigned to the client
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Name of the queue the server set up for the client
+    pub fn queue_name(&self) -> String {
+        self.control.queue_name(self.id)
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let _ = self.control.transition(self.id, READY, RELEASED);
+    }
+}
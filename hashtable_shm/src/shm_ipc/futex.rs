@@ -0,0 +1,172 @@
+use super::Wait;
+use std::hint;
+use std::ptr::null;
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+use std::thread;
+
+/// Wakeup primitive in shared memory based on a Linux futex
+///
+/// Waiters spin briefly and then park on `seq`. Notifiers only bump `seq` and do the wake syscall
+/// if somebody registered in `waiters`, so if the queue rarely runs empty no syscalls are made at all.
+#[repr(C)]
+pub(super) struct Notifier {
+    seq: AtomicU32,
+    waiters: AtomicU32,
+}
+
+/// Process local state to adapt how long we spin before parking
+///
+/// The limit grows while spinning is successful and shrinks when we had to park anyway.
+/// On a single CPU the other side cannot make progress while we spin, so we park right away.
+pub(super) struct AdaptiveSpin {
+    limit: AtomicU32,
+    max: u32,
+}
+
+impl Notifier {
+    pub fn init(&self) {
+        self.seq.store(0, Ordering::Relaxed);
+        self.waiters.store(0, Ordering::Release);
+    }
+
+    /// Calls `poll` until it returns something
+    ///
+    /// - spins for a short time, then parks on the futex until notified
+    /// - returns `None` if `wait` does not allow to wait (any longer)
+    pub fn wait_until<R>(
+        &self,
+        wait: &Wait,
+        spin: &AdaptiveSpin,
+        mut poll: impl FnMut() -> Option<R>,
+    ) -> Option<R> {
+        if let Some(res) = poll() {
+            return Some(res);
+        }
+        if matches!(wait, Wait::None) {
+            return None;
+        }
+
+        let limit = spin.limit.load(Ordering::Relaxed);
+        for step in 0..limit {
+            match step {
+                0..=AdaptiveSpin::MAX_STEP => {
+                    for _ in 0..1 << step {
+                        hint::spin_loop();
+                    }
+                }
+                _ => thread::yield_now(),
+            }
+            if let Some(res) = poll() {
+                spin.success();
+                return Some(res);
+            }
+        }
+        spin.failure();
+
+        loop {
+            if wait.expired() {
+                return poll();
+            }
+
+            // Register before checking again, so a notifier either sees us or we see its data
+            let seq = self.seq.load(Ordering::Acquire);
+            self.waiters.fetch_add(1, Ordering::SeqCst);
+            fence(Ordering::SeqCst);
+
+            let res = match poll() {
+                Some(res) => Some(res),
+                None => {
+                    // Returns immediately if `seq` changed since we read it
+                    futex_wait(&self.seq, seq, wait);
+                    poll()
+                }
+            };
+
+            self.waiters.fetch_sub(1, Ordering::SeqCst);
+            if res.is_some() {
+                return res;
+            }
+        }
+    }
+
+    /// Wakes up one waiter, must be called after the change it waits for was published
+    ///
+    /// Waiters register before checking again, so if we do not see them they see the change.
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        if self.waiters.load(Ordering::Relaxed) > 0 {
+            self.seq.fetch_add(1, Ordering::Release);
+            futex_wake(&self.seq, 1);
+        }
+    }
+
+    /// Wakes up all waiters, must be called after the change they wait for was published
+    pub fn notify_all(&self) {
+        fence(Ordering::SeqCst);
+        if self.waiters.load(Ordering::Relaxed) > 0 {
+            self.seq.fetch_add(1, Ordering::Release);
+            futex_wake(&self.seq, i32::MAX);
+        }
+    }
+}
+
+impl AdaptiveSpin {
+    /// Upper bound for the number of rounds before parking
+    const MAX_LIMIT: u32 = 16;
+    /// Rounds up to this step spin for 2^step iterations, later rounds yield the CPU
+    const MAX_STEP: u32 = 6;
+
+    pub fn new() -> Self {
+        let max = match thread::available_parallelism() {
+            Ok(n) if n.get() > 1 => Self::MAX_LIMIT,
+            _ => 0,
+        };
+
+        Self {
+            limit: AtomicU32::new(max / 2),
+            max,
+        }
+    }
+
+    fn success(&self) {
+        let limit = self.limit.load(Ordering::Relaxed);
+        self.limit
+            .store((limit + 1).min(self.max), Ordering::Relaxed);
+    }
+
+    fn failure(&self) {
+        let limit = self.limit.load(Ordering::Relaxed);
+        self.limit.store(limit.saturating_sub(1), Ordering::Relaxed);
+    }
+}
+
+/// Sleeps while `word` still contains `val` or until the deadline of `wait` passed
+///
+/// Spurious wakeups are possible, the caller has to check its condition again.
+fn futex_wait(word: &AtomicU32, val: u32, wait: &Wait) {
+    let deadline = match wait {
+        Wait::Until(deadline) => deadline as *const libc::timespec,
+        _ => null(),
+    };
+
+    // FUTEX_WAIT_BITSET takes an absolute deadline on CLOCK_MONOTONIC, like our timed condition waits.
+    // The futex is not private, as the waker can be in another process.
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT_BITSET,
+            val,
+            deadline,
+            null::<u32>(),
+            libc::FUTEX_BITSET_MATCH_ANY,
+        );
+    }
+}
+
+/// Wakes up to `count` threads sleeping on `word`
+fn futex_wake(word: &AtomicU32, count: i32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, count);
+    }
+}
@@ -0,0 +1,378 @@
+use super::futex::{AdaptiveSpin, Notifier};
+use super::{align_up, Error, Wait};
+use crate::shm_safe::ShmSafe;
+use std::cell::UnsafeCell;
+use std::mem::{align_of, size_of};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Handle to a lock-free ring buffer in shared memory
+///
+/// Bounded MPMC queue after Dmitry Vyukov: every slot carries a sequence number that tells producers and consumers
+/// whether the slot is ready for them, so the positions only need a compare-and-swap and no lock.
+///
+/// Blocking operations wait on a futex in the shared memory, see `Notifier`.
+///
+/// If a process dies between claiming and publishing a slot, the ring gets stuck at that slot.
+/// Unlike the locked ring this cannot be detected or recovered.
+pub(super) struct RingBuffer<T> {
+    inner: *mut RingBufferInner,
+    slots: *mut Slot<T>,
+    capacity: usize,
+    spin: AdaptiveSpin,
+}
+
+#[repr(C, align(64))]
+/// Keeps a value on its own cache line, so producers and consumers do not contend for the same line
+struct CachePadded<T>(T);
+
+#[repr(C)]
+/// Positions of the lock-free ring
+///
+/// Followed directly by the slots of the ring in the shared memory.
+struct RingBufferInner {
+    enqueue_pos: CachePadded<AtomicUsize>,
+    dequeue_pos: CachePadded<AtomicUsize>,
+    has_data: CachePadded<Notifier>,
+    has_space: CachePadded<Notifier>,
+    closed: AtomicBool,
+}
+
+#[repr(C)]
+/// Entry of the lock-free ring
+///
+/// `seq == pos` means the slot is free for the producer at `pos`,
+/// `seq == pos + 1` means it holds the data for the consumer at `pos`, unless the producer set `skip`.
+struct Slot<T> {
+    seq: AtomicUsize,
+    skip: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+impl<T: ShmSafe> RingBuffer<T> {
+    /// Offset of the first slot from the start of the ring
+    fn slots_offset() -> usize {
+        align_up(size_of::<RingBufferInner>(), align_of::<Slot<T>>())
+    }
+
+    /// Required alignment of a ring
+    pub fn align() -> usize {
+        align_of::<RingBufferInner>().max(align_of::<Slot<T>>())
+    }
+
+    /// Size of a ring holding `capacity` entries
+    pub fn size(capacity: usize) -> usize {
+        Self::slots_offset() + capacity * size_of::<Slot<T>>()
+    }
+
+    /// Creates a handle for the ring at `ptr`
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be aligned to `Self::align()` and valid for `Self::size(capacity)` bytes
+    pub unsafe fn new(ptr: *mut u8, capacity: usize) -> Self {
+        Self {
+            inner: ptr as *mut RingBufferInner,
+            slots: ptr.add(Self::slots_offset()) as *mut Slot<T>,
+            capacity,
+            spin: AdaptiveSpin::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn inner(&self) -> &RingBufferInner {
+        unsafe { &*self.inner }
+    }
+
+    fn slot(&self, pos: usize) -> &Slot<T> {
+        unsafe { &*self.slots.add(pos % self.capacity) }
+    }
+
+    /// Initializes positions, sequence numbers and notifiers
+    ///
+    /// Should only be called once during initial setup of the data structure
+    pub fn init(&self) -> Result<(), Error> {
+        self.inner().enqueue_pos.0.store(0, Ordering::Relaxed);
+        self.inner().dequeue_pos.0.store(0, Ordering::Relaxed);
+        self.inner().has_data.0.init();
+        self.inner().has_space.0.init();
+        self.inner().closed.store(false, Ordering::Relaxed);
+        for pos in 0..self.capacity {
+            self.slot(pos).seq.store(pos, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Claims the next free slot for writing
+    ///
+    /// - waits according to `wait` for a consumer to free a slot if the buffer is full
+    /// - returns `Error::BufferFull` if there is still no space to write
+    /// - returns `Error::Closed` if the ring was closed, even if there is space
+    /// - returns the position of the slot, which has to be passed to `commit` or `abort`
+    pub fn reserve(&self, wait: Wait) -> Result<usize, Error> {
+        self.inner()
+            .has_space
+            .0
+            .wait_until(&wait, &self.spin, || {
+                if self.is_closed() {
+                    return Some(Err(Error::Closed));
+                }
+                match self.try_claim_write() {
+                    Err(Error::BufferFull) => None,
+                    res => Some(res),
+                }
+            })
+            .unwrap_or(Err(Error::BufferFull))
+    }
+
+    /// Pointer to the data of the slot at `pos`
+    pub fn data(&self, pos: usize) -> *mut T {
+        self.slot(pos).data.get()
+    }
+
+    /// Publishes the slot at `pos` claimed by `reserve` and notifies potential readers
+    pub fn commit(&self, pos: usize) {
+        self.publish(pos, false);
+        self.inner().has_data.0.notify();
+    }
+
+    /// Publishes the slot at `pos` claimed by `reserve` as skipped
+    ///
+    /// Consumers cannot run ahead of a claimed slot, so it has to be handed on even if nothing was written.
+    /// They are notified as well, as data published after it might have been waiting for it.
+    pub fn abort(&self, pos: usize) {
+        self.publish(pos, true);
+        self.inner().has_data.0.notify();
+    }
+
+    /// Puts data into buffer
+    ///
+    /// - waits like `reserve` for space
+    /// - notifies potential readers of successful write
+    pub fn put(&self, data: &T, wait: Wait) -> Result<(), Error> {
+        let pos = self.reserve(wait)?;
+        // The slot is ours until we publish it with the new sequence number
+        unsafe {
+            ptr::write(self.data(pos), *data);
+        }
+        self.commit(pos);
+        Ok(())
+    }
+
+    /// Puts as many entries of `data` into the buffer as fit
+    ///
+    /// - waits like `put` until at least one entry fits
+    /// - returns the number of entries written from the start of `data`
+    /// - notifies all potential readers if more than one entry was written
+    pub fn put_many(&self, data: &[T], wait: Wait) -> Result<usize, Error> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let res = self
+            .inner()
+            .has_space
+            .0
+            .wait_until(&wait, &self.spin, || {
+                if self.is_closed() {
+                    return Some(Err(Error::Closed));
+                }
+                let count = data
+                    .iter()
+                    .take_while(|entry| self.try_put(entry).is_ok())
+                    .count();
+                match count {
+                    0 => None,
+                    count => Some(Ok(count)),
+                }
+            })
+            .unwrap_or(Err(Error::BufferFull));
+
+        match res {
+            Ok(1) => self.inner().has_data.0.notify(),
+            Ok(_) => self.inner().has_data.0.notify_all(),
+            Err(_) => (),
+        }
+        res
+    }
+
+    fn try_put(&self, data: &T) -> Result<(), Error> {
+        let pos = self.try_claim_write()?;
+        unsafe {
+            ptr::write(self.data(pos), *data);
+        }
+        self.publish(pos, false);
+        Ok(())
+    }
+
+    fn try_claim_write(&self) -> Result<usize, Error> {
+        let enqueue_pos = &self.inner().enqueue_pos.0;
+        let mut pos = enqueue_pos.load(Ordering::Relaxed);
+
+        loop {
+            let seq = self.slot(pos).seq.load(Ordering::Acquire);
+
+            match (seq as isize).wrapping_sub(pos as isize) {
+                0 => match enqueue_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Ok(pos),
+                    Err(current) => pos = current,
+                },
+                // The slot still holds data from the previous round
+                dif if dif < 0 => return Err(Error::BufferFull),
+                // Another producer was faster
+                _ => pos = enqueue_pos.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Hands the claimed slot at `pos` to the consumer of this round
+    fn publish(&self, pos: usize, skip: bool) {
+        let slot = self.slot(pos);
+        slot.skip.store(skip, Ordering::Relaxed);
+        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Claims the next published slot for reading
+    ///
+    /// - waits according to `wait` for a producer to publish data if none is there
+    /// - returns `Error::BufferEmpty` when not waiting or `Error::Timeout` after the deadline if there is still no data
+    /// - returns `Error::Closed` if the ring was closed and all remaining data was read
+    /// - returns the position of the slot, which has to be passed to `release`
+    pub fn peek(&self, wait: Wait) -> Result<usize, Error> {
+        self.inner()
+            .has_data
+            .0
+            .wait_until(&wait, &self.spin, || match self.try_claim_read() {
+                Err(Error::BufferEmpty) if self.is_closed() => Some(Err(Error::Closed)),
+                Err(Error::BufferEmpty) => None,
+                res => Some(res),
+            })
+            .unwrap_or(Err(match wait {
+                Wait::None => Error::BufferEmpty,
+                _ => Error::Timeout,
+            }))
+    }
+
+    /// Frees the slot at `pos` claimed by `peek` and notifies potential writers
+    pub fn release(&self, pos: usize) {
+        self.free(pos);
+        self.inner().has_space.0.notify();
+    }
+
+    /// Gets data from buffer
+    ///
+    /// - waits like `peek` for data
+    /// - notifies potential writers that space was freed
+    pub fn get(&self, wait: Wait) -> Result<T, Error> {
+        let pos = self.peek(wait)?;
+        let data = unsafe { ptr::read(self.data(pos)) };
+        self.release(pos);
+        Ok(data)
+    }
+
+    /// Gets up to `max` entries from the buffer
+    ///
+    /// - waits like `get` until at least one entry is there, unless `max` is `0`
+    /// - notifies all potential writers if more than one entry was read
+    pub fn get_many(&self, max: usize, wait: Wait) -> Result<Vec<T>, Error> {
+        if max == 0 {
+            return Ok(vec![]);
+        }
+
+        let res = self
+            .inner()
+            .has_data
+            .0
+            .wait_until(&wait, &self.spin, || match self.try_get() {
+                Err(Error::BufferEmpty) if self.is_closed() => Some(Err(Error::Closed)),
+                Err(Error::BufferEmpty) => None,
+                Err(e) => Some(Err(e)),
+                Ok(first) => {
+                    let mut data = vec![first];
+                    while data.len() < max {
+                        match self.try_get() {
+                            Ok(entry) => data.push(entry),
+                            Err(_) => break,
+                        }
+                    }
+                    Some(Ok(data))
+                }
+            })
+            .unwrap_or(Err(match wait {
+                Wait::None => Error::BufferEmpty,
+                _ => Error::Timeout,
+            }));
+
+        match &res {
+            Ok(data) if data.len() == 1 => self.inner().has_space.0.notify(),
+            Ok(_) => self.inner().has_space.0.notify_all(),
+            Err(_) => (),
+        }
+        res
+    }
+
+    /// Marks the ring as closed and wakes up all waiting readers and writers
+    pub fn close(&self) {
+        self.inner().closed.store(true, Ordering::Release);
+        self.inner().has_data.0.notify_all();
+        self.inner().has_space.0.notify_all();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner().closed.load(Ordering::Acquire)
+    }
+
+    fn try_get(&self) -> Result<T, Error> {
+        let pos = self.try_claim_read()?;
+        let data = unsafe { ptr::read(self.data(pos)) };
+        self.free(pos);
+        Ok(data)
+    }
+
+    /// Claims the next published slot that was not skipped
+    fn try_claim_read(&self) -> Result<usize, Error> {
+        let dequeue_pos = &self.inner().dequeue_pos.0;
+        let mut pos = dequeue_pos.load(Ordering::Relaxed);
+
+        loop {
+            let slot = self.slot(pos);
+            let seq = slot.seq.load(Ordering::Acquire);
+
+            match (seq as isize).wrapping_sub(pos.wrapping_add(1) as isize) {
+                0 => match dequeue_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) if slot.skip.load(Ordering::Relaxed) => {
+                        self.free(pos);
+                        self.inner().has_space.0.notify();
+                        pos = dequeue_pos.load(Ordering::Relaxed);
+                    }
+                    Ok(_) => return Ok(pos),
+                    Err(current) => pos = current,
+                },
+                // Nothing was published for this position yet
+                dif if dif < 0 => return Err(Error::BufferEmpty),
+                // Another consumer was faster
+                _ => pos = dequeue_pos.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Hands the claimed slot at `pos` to the producer of the next round
+    fn free(&self, pos: usize) {
+        self.slot(pos)
+            .seq
+            .store(pos.wrapping_add(self.capacity), Ordering::Release);
+    }
+}
@@ -0,0 +1,65 @@
+use std::fs;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+/// Identifies a process by its PID and start time
+///
+/// PIDs are reused by the kernel, so only the combination tells whether the original process is still running.
+/// A start time of `0` means it was not available when the record was made and only the PID is checked.
+pub(super) struct Process {
+    pub pid: u32,
+    pub start_time: u64,
+}
+
+impl Process {
+    /// The calling process
+    pub fn current() -> Self {
+        let pid = std::process::id();
+        Self {
+            pid,
+            start_time: stat(pid).map_or(0, |stat| stat.start_time),
+        }
+    }
+
+    /// Checks if the recorded process is still running
+    ///
+    /// Zombies count as dead, as they cannot touch the shared memory anymore.
+    pub fn is_alive(&self) -> bool {
+        // PID 0 would address our own process group
+        if self.pid == 0 || self.pid > libc::pid_t::MAX as u32 {
+            return false;
+        }
+        if unsafe { libc::kill(self.pid as libc::pid_t, 0) } != 0
+            && std::io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+        {
+            return false;
+        }
+
+        // Without procfs we have to trust the PID
+        match stat(self.pid) {
+            Some(stat) => {
+                !matches!(stat.state, 'Z' | 'X')
+                    && (self.start_time == 0 || self.start_time == stat.start_time)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Fields of `/proc/<pid>/stat` we are interested in
+struct Stat {
+    state: char,
+    /// Clock ticks after boot at which the process started
+    start_time: u64,
+}
+
+fn stat(pid: u32) -> Option<Stat> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+
+    // The command name in the second field can contain spaces and parentheses itself,
+    // so the remaining fields start after the last closing parenthesis with the state as third field.
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let start_time = fields.nth(18)?.parse().ok()?;
+
+    Some(Stat { state, start_time })
+}
@@ -0,0 +1,80 @@
+use super::Error;
+use rustix::fd::{AsFd, BorrowedFd, OwnedFd};
+use rustix::io::{IoSlice, IoSliceMut};
+use rustix::net::{
+    recvmsg, sendmsg, sockopt, RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags,
+    SendAncillaryBuffer, SendAncillaryMessage, SendFlags,
+};
+use std::os::unix::net::UnixStream;
+
+/// Payload accompanying the file descriptor, as some data has to be sent along with it
+const HANDOVER: &[u8] = b"Q";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+/// Process on the other end of a Unix socket, as reported by the kernel
+pub struct PeerCredentials {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Looks up who is connected to `stream` with `SO_PEERCRED`
+///
+/// The kernel records the credentials when the connection is made, so the peer cannot fake them.
+pub fn peer_credentials(stream: &UnixStream) -> Result<PeerCredentials, Error> {
+    let cred = sockopt::get_socket_peercred(stream)?;
+    Ok(PeerCredentials {
+        pid: cred.pid.as_raw_nonzero().get() as u32,
+        uid: cred.uid.as_raw(),
+        gid: cred.gid.as_raw(),
+    })
+}
+
+/// Most descriptors sent at once: the memory of a queue and its eventfd
+const MAX_FDS: usize = 2;
+
+/// Sends `fds` to the other end of `stream` with `SCM_RIGHTS`
+pub(super) fn send_fds(stream: &UnixStream, fds: &[BorrowedFd<'_>]) -> Result<(), Error> {
+    debug_assert!(fds.len() <= MAX_FDS);
+    let mut space = [0; rustix::cmsg_space!(ScmRights(MAX_FDS))];
+    let mut control = SendAncillaryBuffer::new(&mut space);
+    control.push(SendAncillaryMessage::ScmRights(fds));
+
+    sendmsg(
+        stream,
+        &[IoSlice::new(HANDOVER)],
+        &mut control,
+        SendFlags::NOSIGNAL,
+    )?;
+    Ok(())
+}
+
+/// Receives the file descriptors sent with `send_fds`
+///
+/// returns `Error::Rejected` if the connection was closed without any
+pub(super) fn recv_fds(stream: &UnixStream) -> Result<Vec<OwnedFd>, Error> {
+    let mut buf = [0u8; HANDOVER.len()];
+    let mut space = [0; rustix::cmsg_space!(ScmRights(MAX_FDS))];
+    let mut control = RecvAncillaryBuffer::new(&mut space);
+
+    // Descriptors must not leak into processes we spawn
+    let res = recvmsg(
+        stream.as_fd(),
+        &mut [IoSliceMut::new(&mut buf)],
+        &mut control,
+        RecvFlags::CMSG_CLOEXEC,
+    )?;
+
+    let fds: Vec<OwnedFd> = control
+        .drain()
+        .filter_map(|msg| match msg {
+            RecvAncillaryMessage::ScmRights(fds) => Some(fds),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    match res.bytes == 0 || fds.is_empty() {
+        true => Err(Error::Rejected),
+        false => Ok(fds),
+    }
+}
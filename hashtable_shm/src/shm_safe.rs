@@ -0,0 +1,64 @@
+pub use hashtable_shm_derive::ShmSafe;
+
+/// Marker for types that can be placed in shared memory
+///
+/// The value is copied bytewise into memory that is mapped by another process, so it must not contain pointers,
+/// references or anything else that is only meaningful inside the address space of one process.
+///
+/// Use `#[derive(ShmSafe)]` for `#[repr(C)]` structs, which checks that every field implements `ShmSafe`:
+///
+/// ```
+/// use hashtable_shm::shm_safe::ShmSafe;
+///
+/// #[repr(C)]
+/// #[derive(Clone, Copy, ShmSafe)]
+/// struct Point {
+///     x: u32,
+///     y: [u8; 4],
+/// }
+/// ```
+///
+/// Types owning heap memory are rejected:
+///
+/// ```compile_fail
+/// use hashtable_shm::shm_safe::ShmSafe;
+///
+/// #[repr(C)]
+/// #[derive(Clone, ShmSafe)]
+/// struct Name {
+///     name: String,
+/// }
+/// ```
+///
+/// So are enums, as not every bit pattern is a valid discriminant:
+///
+/// ```compile_fail
+/// use hashtable_shm::shm_safe::ShmSafe;
+///
+/// #[repr(u32)]
+/// #[derive(Clone, Copy, ShmSafe)]
+/// enum Kind {
+///     Read = 0,
+///     Write = 1,
+/// }
+/// ```
+///
+/// # Safety
+///
+/// Implementors must be plain-old-data: no pointers or references, no drop glue and a layout that is fixed by
+/// `#[repr(C)]` or a primitive representation.
+///
+/// Every bit pattern must be a valid value, as the other process can write arbitrary bytes into the segment.
+/// That rules out `bool`, `char`, enums and references, store them as plain integers and decode them
+/// with `TryFrom` instead.
+pub unsafe trait ShmSafe: Copy + 'static {}
+
+macro_rules! impl_shm_safe {
+    ($($t:ty),*) => {
+        $(unsafe impl ShmSafe for $t {})*
+    };
+}
+
+impl_shm_safe!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: ShmSafe, const N: usize> ShmSafe for [T; N] {}
@@ -1,8 +1,8 @@
-use std::{env, process::ExitCode, str::FromStr, sync::Arc, thread, time};
+use std::{env, os::unix::net::UnixStream, path::PathBuf, process::ExitCode, time};
 
 use thiserror::Error;
 
-use hashtable_shm::shm_ipc::{self, Request};
+use hashtable_shm::{client, shm_ipc};
 
 #[derive(Error, Debug)]
 pub enum ClientError {
@@ -14,24 +14,31 @@ pub enum ClientError {
 
     #[error("Unexpected Token: {0}")]
     UnexpectedToken(String),
-
-    #[error("Parser Error: {0}")]
-    ParserError(<u32 as FromStr>::Err),
 }
 
-// Key and Value type for hashtable and buffer
-type TK = u32;
-type TV = u32;
+// Key and Value type for hashtable
+type TK = Vec<u8>;
+type TV = Vec<u8>;
+
+type Queue = client::Queue;
+
+/// How long we wait for the server to make progress before we give up
+const SERVER_TIMEOUT: time::Duration = time::Duration::from_secs(5);
 
 #[derive(Clone, Debug)]
 pub enum Operation {
     Read { key: TK },
     Insert { key: TK, value: TV },
-    Delete { key: TV },
+    Delete { key: TK },
 }
 
 struct Args {
-    client_id: String,
+    /// Prefix of the segments of the server
+    prefix: String,
+    /// Unix socket of a server handing out anonymous shared memory
+    socket: Option<PathBuf>,
+    /// Priority lane of the requests
+    priority: u32,
     operations: Vec<Operation>,
 }
 
@@ -43,27 +50,39 @@ impl Args {
         // Skip first as this is the program name
         it.next().ok_or(ClientError::ArgumentsMissing)?;
 
-        let client_id = format!(
-            "hashtable-{}",
-            it.next().ok_or(ClientError::ArgumentsMissing)?
-        );
-
+        let mut prefix = shm_ipc::DEFAULT_PREFIX.to_string();
+        let mut socket = None;
+        let mut priority = 0;
         let mut operations: Vec<_> = vec![];
 
         while let Some(token) = it.next() {
             match token.as_str() {
+                "--prefix" => {
+                    prefix = it.next().ok_or(ClientError::ArgumentsMissing)?.clone();
+                }
+                "--socket" => {
+                    socket = Some(PathBuf::from(
+                        it.next().ok_or(ClientError::ArgumentsMissing)?,
+                    ));
+                }
+                "--priority" => {
+                    let value = it.next().ok_or(ClientError::ArgumentsMissing)?;
+                    priority = value
+                        .parse()
+                        .map_err(|_| ClientError::ParserFailed(value.clone()))?;
+                }
                 "insert" => {
                     operations.push(Operation::Insert {
                         key: it
                             .next()
                             .ok_or(ClientError::ArgumentsMissing)?
-                            .parse()
-                            .map_err(ClientError::ParserError)?,
+                            .as_bytes()
+                            .to_vec(),
                         value: it
                             .next()
                             .ok_or(ClientError::ArgumentsMissing)?
-                            .parse()
-                            .map_err(ClientError::ParserError)?,
+                            .as_bytes()
+                            .to_vec(),
                     });
                 }
                 "delete" => {
@@ -71,8 +90,8 @@ impl Args {
                         key: it
                             .next()
                             .ok_or(ClientError::ArgumentsMissing)?
-                            .parse()
-                            .map_err(ClientError::ParserError)?,
+                            .as_bytes()
+                            .to_vec(),
                     });
                 }
                 "read" => {
@@ -80,8 +99,8 @@ impl Args {
                         key: it
                             .next()
                             .ok_or(ClientError::ArgumentsMissing)?
-                            .parse()
-                            .map_err(ClientError::ParserError)?,
+                            .as_bytes()
+                            .to_vec(),
                     });
                 }
                 e => return Err(ClientError::UnexpectedToken(e.to_string())),
@@ -89,7 +108,9 @@ impl Args {
         }
 
         Ok(Self {
-            client_id,
+            prefix,
+            socket,
+            priority,
             operations,
         })
     }
@@ -103,77 +124,100 @@ fn main() -> ExitCode {
             return ExitCode::FAILURE;
         }
     };
-    let ipc_client: Arc<shm_ipc::ShmQueue<u32, u32>> =
-        match shm_ipc::ShmQueue::new(&args.client_id, false) {
-            Ok(client) => Arc::new(client),
-            Err(e) => {
-                eprintln!("Failed to connect to shared memory: {e}");
-                return ExitCode::FAILURE;
-            }
-        };
-
-    let ipc_read = ipc_client.clone();
-    let count = args.operations.len();
-    let handle = thread::spawn(move || {
-        for _ in 0..(count) {
-            match ipc_read.response_get() {
-                Ok(response) => match response.error {
-                    true => {
-                        eprintln!("Failed to do the given operation");
-                    }
-                    false => {
-                        if response.operation == shm_ipc::Operation::Read {
-                            println!("Key: {}, Value: {}", response.key, response.val)
-                        }
-                    }
-                },
-                Err(_) => {
-                    eprintln!("Failed to get response back from server");
+    // The server tears down our queue once we dropped our connection or registration
+    let control;
+    let _registration;
+    let _connection;
+    let ipc = match &args.socket {
+        Some(path) => {
+            let stream = match UnixStream::connect(path) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to connect to server: {e}");
+                    return ExitCode::FAILURE;
                 }
             };
+            let ipc = Queue::receive_from(&stream);
+            _connection = stream;
+            ipc
         }
-    });
-
-    let mut exit_code = ExitCode::SUCCESS;
-
-    for (counter, operation) in args.operations.iter().enumerate() {
-        let request: Request<u32, u32> = match operation {
-            Operation::Read { key } => Request {
-                operation: shm_ipc::Operation::Read,
-                key: *key,
-                val: 0,
-                counter,
-            },
-            Operation::Insert { key, value } => Request {
-                operation: shm_ipc::Operation::Insert,
-                key: *key,
-                val: *value,
-                counter,
-            },
-            Operation::Delete { key } => Request {
-                operation: shm_ipc::Operation::Delete,
-                key: *key,
-                val: 0,
-                counter,
-            },
-        };
+        None => {
+            control = match shm_ipc::Control::open(&args.prefix) {
+                Ok(control) => control,
+                Err(e) => {
+                    eprintln!("Failed to connect to server: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let registration = match control.register(SERVER_TIMEOUT) {
+                Ok(registration) => registration,
+                Err(e) => {
+                    eprintln!("Failed to register with server: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let ipc = Queue::new(&registration.queue_name(), false);
+            _registration = registration;
+            ipc
+        }
+    };
+    let mut client = match ipc {
+        Ok(ipc) => client::Client::new(ipc),
+        Err(e) => {
+            eprintln!("Failed to connect to shared memory: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    client.set_timeout(SERVER_TIMEOUT);
+    client.set_priority(args.priority);
+
+    let commands: Vec<_> = args
+        .operations
+        .iter()
+        .map(|operation| match operation {
+            Operation::Read { key } => client::Command::Read(key),
+            Operation::Insert { key, value } => client::Command::Insert(key, value),
+            Operation::Delete { key } => client::Command::Delete(key),
+        })
+        .collect();
+    let outcomes = match client.execute(&commands) {
+        Ok(outcomes) => outcomes,
+        Err(e) => {
+            eprintln!("Failed to execute operations: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
 
-        loop {
-            match ipc_client.request_put(&request) {
-                Ok(_) => break,
-                Err(shm_ipc::Error::BufferFull) => thread::sleep(time::Duration::from_micros(10)), // We don't have an extra lock for this, so just  wait
-                Err(_) => {
-                    eprintln!("Something went wrong while trying to write to buffer");
-                    break;
+    for (operation, outcome) in args.operations.iter().zip(outcomes) {
+        let (name, key) = match operation {
+            Operation::Read { key } => ("Read", key),
+            Operation::Insert { key, .. } => ("Insert", key),
+            Operation::Delete { key } => ("Delete", key),
+        };
+        match outcome {
+            Ok(value) => {
+                if let Operation::Read { .. } = operation {
+                    println!(
+                        "Key: {}, Value: {}",
+                        String::from_utf8_lossy(key),
+                        String::from_utf8_lossy(&value)
+                    );
                 }
             }
+            Err(e) => {
+                let reason = match e {
+                    client::Error::Failed(status) => status.to_string(),
+                    e => e.to_string(),
+                };
+                eprintln!(
+                    "{} of key {} failed: {}",
+                    name,
+                    String::from_utf8_lossy(key),
+                    reason
+                );
+            }
         }
     }
 
-    match handle.join() {
-        Ok(_) => (),
-        Err(_) => exit_code = ExitCode::FAILURE,
-    }
-
-    exit_code
+    ExitCode::SUCCESS
 }
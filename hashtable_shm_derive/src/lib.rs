@@ -0,0 +1,86 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Error};
+
+/// Derives `ShmSafe` for `#[repr(C)]` structs
+///
+/// Every field has to implement `ShmSafe` itself and type parameters get a `ShmSafe` bound.
+/// Enums are rejected, as not every bit pattern is a valid discriminant.
+#[proc_macro_derive(ShmSafe)]
+pub fn derive_shm_safe(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(mut input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let repr = repr(&input)?;
+
+    let fields: Vec<syn::Type> = match &input.data {
+        Data::Struct(data) => {
+            if !repr.iter().any(|r| r == "C" || r == "transparent") {
+                return Err(Error::new(
+                    Span::call_site(),
+                    "ShmSafe can only be derived for structs with #[repr(C)] or #[repr(transparent)]",
+                ));
+            }
+            data.fields.iter().map(|f| f.ty.clone()).collect()
+        }
+        Data::Enum(_) => {
+            return Err(Error::new(
+                Span::call_site(),
+                "ShmSafe cannot be derived for enums, store the discriminant as integer instead",
+            ))
+        }
+        Data::Union(_) => {
+            return Err(Error::new(
+                Span::call_site(),
+                "ShmSafe cannot be derived for unions",
+            ))
+        }
+    };
+
+    let shm_safe: syn::Path = parse_quote!(::hashtable_shm::shm_safe::ShmSafe);
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#shm_safe));
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        const _: () = {
+            fn assert_shm_safe<T: #shm_safe>() {}
+
+            #[allow(dead_code)]
+            fn assert_fields #impl_generics () #where_clause {
+                #(assert_shm_safe::<#fields>();)*
+            }
+        };
+
+        unsafe impl #impl_generics #shm_safe for #name #ty_generics #where_clause {}
+    })
+}
+
+/// Collects the representation hints given in `#[repr(...)]`
+fn repr(input: &DeriveInput) -> Result<Vec<String>, Error> {
+    let mut repr = vec![];
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            if let Some(ident) = meta.path.get_ident() {
+                repr.push(ident.to_string());
+            }
+            // Skip arguments such as in `align(8)`
+            if meta.input.peek(syn::token::Paren) {
+                let _args;
+                syn::parenthesized!(_args in meta.input);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(repr)
+}
@@ -1,41 +1,136 @@
 use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
     process::ExitCode,
-    sync::{mpsc, Arc},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
     thread, time,
 };
 
-use clap::{command, Parser};
+use clap::Parser;
+use rustix::event::{poll, PollFd, PollFlags};
 
 use hashtable_shm::{
     hashtable,
-    shm_ipc::{self, Operation},
+    shm_ipc::{self, Blob, ControlEvent, Operation, Status},
 };
 
+// Key and Value type for hashtable
+type TK = Vec<u8>;
+type TV = Vec<u8>;
+
+type Queue = shm_ipc::ShmQueue<Blob, Blob>;
+type Request = shm_ipc::Request<Blob, Blob>;
+
+/// How long a worker waits for the client to release space in the arena before it answers with `Status::Busy`
+const ARENA_TIMEOUT: time::Duration = time::Duration::from_millis(100);
+
+/// How long queued requests are answered on shutdown before the queues are closed for good
+const SHUTDOWN_GRACE: time::Duration = time::Duration::from_secs(1);
+
+/// How often we send heartbeats and look for clients that died or stopped sending theirs
+///
+/// Half of `HEARTBEAT_INTERVAL`, as events from other clients can delay a check by up to one interval.
+const CONTROL_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     bucket_size: usize,
     clients: usize,
     threads: usize,
+
+    /// Number of requests that can be queued per client and lane
+    #[arg(long, default_value_t = shm_ipc::DEFAULT_QUEUE_DEPTH)]
+    queue_depth: usize,
+
+    /// Number of priority lanes for requests, higher lanes are served first
+    #[arg(long, default_value_t = 1)]
+    lanes: usize,
+
+    /// Number of responses that can be queued per client [default: queue depth]
+    #[arg(long)]
+    response_queue_depth: Option<usize>,
+
+    /// Use lock-free rings instead of rings protected by a mutex
+    #[arg(long)]
+    lock_free: bool,
+
+    /// Maximum number of requests a worker takes from the queue at once
+    #[arg(long, default_value_t = 8)]
+    batch_size: usize,
+
+    /// Execute the requests for one key in the order the client sent them, by routing them to workers by key
+    ///
+    /// Only possible with a single lane.
+    #[arg(long)]
+    ordered: bool,
+
+    /// Take over existing segments even if the server that created them is still running
+    #[arg(long)]
+    force: bool,
+
+    /// Hand anonymous shared memory to clients connecting to this Unix socket instead of creating named segments
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    /// Hand socket clients an eventfd that signals new responses, so they can wait in their own event loop
+    #[arg(long, requires = "socket")]
+    event_fd: bool,
+
+    /// Prefix of the names of the segments, so several servers can run side by side
+    #[arg(long, default_value = shm_ipc::DEFAULT_PREFIX)]
+    prefix: String,
+
+    /// Permissions of the segments and the socket in octal
+    #[arg(long, default_value = "600", value_parser = parse_mode)]
+    mode: u32,
+
+    /// Group owning the segments and the socket, by name or id
+    #[arg(long, value_parser = parse_group)]
+    group: Option<u32>,
+}
+
+/// Queue of a registered client and the threads working on it
+struct Client {
+    ipc: Arc<Queue>,
+    workers: Vec<thread::JoinHandle<()>>,
+    /// The queue was closed because the client stopped sending heartbeats, see `Client::reset`
+    reset: bool,
 }
 
 fn main() -> ExitCode {
     let args = Args::parse();
-    let table: Arc<hashtable::HashTable<u32, u32>> =
-        match hashtable::HashTable::new(args.bucket_size) {
-            Ok(t) => Arc::new(t),
-            Err(e) => {
-                eprintln!("Failed to create hashtable: {}", e);
-                return ExitCode::FAILURE;
-            }
-        };
+    // Requests for one key may be sent in different lanes, which are served in no particular order
+    if args.ordered && args.lanes > 1 {
+        eprintln!(
+            "--ordered cannot keep the order of requests across priority lanes, use --lanes 1"
+        );
+        return ExitCode::FAILURE;
+    }
+    let table: Arc<hashtable::HashTable<TK, TV>> = match hashtable::HashTable::new(args.bucket_size)
+    {
+        Ok(t) => Arc::new(t),
+        Err(e) => {
+            eprintln!("Failed to create hashtable: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
 
     // Setup Ctrl-C handler with channel
     let (tx, rx) = mpsc::channel();
-    let tx_handler = tx.clone();
     if ctrlc::set_handler(move || {
-        tx_handler
-            .send(ExitCode::SUCCESS)
+        tx.send(ExitCode::SUCCESS)
             .expect("Error sending shutdown event");
     })
     .is_err()
@@ -44,102 +139,662 @@ fn main() -> ExitCode {
         return ExitCode::FAILURE;
     }
 
-    let mut ipcs: Vec<_> = vec![];
-    for client_id in 0..args.clients {
-        let ipc = match shm_ipc::ShmQueue::new(format!("hashtable-{}", client_id).as_str(), true) {
-            Ok(ipc) => Arc::new(ipc),
-            Err(_) => {
-                eprintln!("Failed to create shared memory for client {}", client_id);
-                tx.clone()
-                    .send(ExitCode::FAILURE)
-                    .expect("Error sending shutdown event");
-                break;
-            }
-        };
-        ipcs.push(ipc.clone());
-        for _ in 0..args.threads {
-            //let name_ipc = name.clone();
-            let t_table = table.clone();
-            let ipc_client = ipc.clone();
-            let _ = thread::spawn(move || loop {
-                if let Ok(request) = ipc_client.request_get() {
-                    println!("Got request: {:?}", request);
-
-                    let response = match request.operation {
-                        Operation::Read => match t_table.read(&request.key) {
-                            Some(value) => shm_ipc::Response {
-                                operation: Operation::Read,
-                                error: false,
-                                key: request.key,
-                                val: value,
-                                counter: request.counter,
-                            },
-                            None => shm_ipc::Response {
-                                operation: Operation::Read,
-                                error: true,
-                                key: request.key,
-                                val: 0,
-                                counter: request.counter,
-                            },
-                        },
-                        Operation::Insert => match t_table.add(request.key, request.val) {
-                            Ok(_) => shm_ipc::Response {
-                                operation: Operation::Insert,
-                                error: false,
-                                key: request.key,
-                                val: request.val,
-                                counter: request.counter,
-                            },
-                            Err(_) => shm_ipc::Response {
-                                operation: Operation::Insert,
-                                error: true,
-                                key: request.key,
-                                val: request.val,
-                                counter: request.counter,
-                            },
-                        },
-                        Operation::Delete => match t_table.delete(&request.key) {
-                            Ok(()) => shm_ipc::Response {
-                                operation: Operation::Delete,
-                                error: false,
-                                key: request.key,
-                                val: 0,
-                                counter: request.counter,
-                            },
-                            Err(_) => shm_ipc::Response {
-                                operation: Operation::Delete,
-                                error: true,
-                                key: request.key,
-                                val: 0,
-                                counter: request.counter,
-                            },
-                        },
-                    };
-                    loop {
-                        match ipc_client.response_put(&response) {
-                            Ok(_) => break,
-                            Err(shm_ipc::Error::BufferFull) => {
-                                thread::sleep(time::Duration::from_micros(10))
-                            } // We don't have an extra lock for this, so just wait
-                            Err(_) => {
-                                eprintln!("Something went wrong while trying to write to buffer");
-                                break;
-                            }
-                        }
-                    }
+    let config = shm_ipc::QueueConfig {
+        request_capacity: args.queue_depth,
+        lanes: args.lanes,
+        response_capacity: args.response_queue_depth.unwrap_or(args.queue_depth),
+        ring: match args.lock_free {
+            true => shm_ipc::RingKind::LockFree,
+            false => shm_ipc::RingKind::Locked,
+        },
+        force: args.force,
+        mode: args.mode,
+        group: args.group,
+        event_fd: args.event_fd,
+    };
+
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    // Every worker holds a sender, so the channel disconnects once all of them exited
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+
+    let (control, serve_thread) = match &args.socket {
+        Some(path) => {
+            let listener = match bind(path, &config) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Failed to listen on {}: {}", path.display(), e);
+                    return ExitCode::FAILURE;
                 }
+            };
+            let shutting_down = shutting_down.clone();
+            let serve_thread = thread::spawn(move || {
+                serve_socket(listener, &table, &config, &args, &shutting_down, done_tx)
             });
+            (None, serve_thread)
         }
-    }
+        None => {
+            let control = match shm_ipc::Control::create(&args.prefix, args.clients, &config) {
+                Ok(control) => Arc::new(control),
+                Err(e) => {
+                    eprintln!("Failed to create control segment: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let serve_thread = {
+                let control = control.clone();
+                let shutting_down = shutting_down.clone();
+                thread::spawn(move || {
+                    serve(&control, &table, &config, &args, &shutting_down, done_tx)
+                })
+            };
+            (Some(control), serve_thread)
+        }
+    };
 
     println!("Use Ctrl-C to stop server...");
     let exit_code = rx.recv().expect("Cloud not wait for shutdown handler");
-    for ipc in ipcs {
-        match ipc.stop() {
+    println!("Shutting down...");
+    shutting_down.store(true, Ordering::Relaxed);
+    // No new clients can register, the serving thread hands over the clients that are still connected
+    if let Some(control) = &control {
+        if let Err(e) = control.stop().and_then(|_| control.close()) {
+            eprintln!("Failed to close control segment: {}", e);
+        }
+    }
+    let clients = serve_thread.join().unwrap_or_default();
+    for client in clients.values() {
+        match client.ipc.stop() {
             Ok(()) => (),
             Err(_) => eprint!("failed to stop ipc"),
         }
+        // The workers answer the requests that are still queued and exit afterwards
+        if let Err(e) = client.ipc.close_requests() {
+            eprintln!("Failed to close ipc: {}", e);
+        }
+    }
+
+    // Workers can be stuck on clients that do not take their responses anymore
+    let _ = done_rx.recv_timeout(SHUTDOWN_GRACE);
+    for client in clients.into_values() {
+        client.shutdown();
     }
-    println!("Shutting down...");
     exit_code
 }
+
+/// Sets up a queue with workers for every client that registers and tears it down again once the client is gone
+///
+/// returns the clients that are still connected after the control segment was closed
+fn serve(
+    control: &shm_ipc::Control,
+    table: &Arc<hashtable::HashTable<TK, TV>>,
+    config: &shm_ipc::QueueConfig,
+    args: &Args,
+    shutting_down: &Arc<AtomicBool>,
+    done_tx: mpsc::Sender<()>,
+) -> HashMap<usize, Client> {
+    let mut clients = HashMap::new();
+    let mut last_check = time::Instant::now();
+    let answer = |res: Result<(), shm_ipc::Error>| {
+        if let Err(e) = res {
+            eprintln!("Failed to answer client: {}", e);
+        }
+    };
+
+    loop {
+        let events = match control.poll(CONTROL_INTERVAL) {
+            Ok(events) => events,
+            Err(shm_ipc::Error::Closed) => return clients,
+            Err(e) => {
+                eprintln!("Failed to wait for clients: {}", e);
+                return clients;
+            }
+        };
+
+        for event in events {
+            match event {
+                ControlEvent::Connect(client_id) => {
+                    match Queue::with_config(&control.queue_name(client_id), true, config) {
+                        Ok(ipc) => {
+                            let client =
+                                Client::start(client_id, ipc, table, args, shutting_down, &done_tx);
+                            clients.insert(client_id, client);
+                            println!("Client {} connected", client_id);
+                            answer(control.accept(client_id));
+                        }
+                        Err(e) => {
+                            eprintln!(
+                                "Failed to create shared memory for client {}: {}",
+                                client_id, e
+                            );
+                            answer(control.reject(client_id));
+                        }
+                    }
+                }
+                ControlEvent::Disconnect(client_id) => {
+                    if let Some(client) = clients.remove(&client_id) {
+                        client.shutdown();
+                        println!("Client {} disconnected", client_id);
+                    }
+                    answer(control.free(client_id));
+                }
+            }
+        }
+        check_clients(&mut clients, &mut last_check);
+    }
+}
+
+/// Hands each client connecting to `listener` its own queue in anonymous memory
+///
+/// Clients keep their connection open while they use the queue, so we tear it down once the connection is closed.
+/// Only processes of our own user or with the configured group as primary group are served.
+///
+/// returns the clients that are still connected once we are shutting down
+fn serve_socket(
+    listener: UnixListener,
+    table: &Arc<hashtable::HashTable<TK, TV>>,
+    config: &shm_ipc::QueueConfig,
+    args: &Args,
+    shutting_down: &Arc<AtomicBool>,
+    done_tx: mpsc::Sender<()>,
+) -> HashMap<usize, Client> {
+    let mut clients: HashMap<usize, Client> = HashMap::new();
+    let mut connections: HashMap<usize, UnixStream> = HashMap::new();
+    let mut last_check = time::Instant::now();
+    let uid = unsafe { libc::getuid() };
+
+    while !shutting_down.load(Ordering::Relaxed) {
+        let ids: Vec<usize> = connections.keys().copied().collect();
+        let mut fds: Vec<_> = ids
+            .iter()
+            .map(|client_id| PollFd::new(&connections[client_id], PollFlags::IN))
+            .collect();
+        fds.push(PollFd::new(&listener, PollFlags::IN));
+        match poll(&mut fds, CONTROL_INTERVAL.as_millis() as i32) {
+            Ok(_) | Err(rustix::io::Errno::INTR) => (),
+            Err(e) => {
+                eprintln!("Failed to wait for clients: {}", e);
+                break;
+            }
+        }
+        let pending = !fds[ids.len()].revents().is_empty();
+        // Clients never write to the connection, so it only becomes readable once they are gone
+        let closed: Vec<usize> = ids
+            .into_iter()
+            .zip(&fds)
+            .filter(|(_, fd)| !fd.revents().is_empty())
+            .map(|(client_id, _)| client_id)
+            .collect();
+        drop(fds);
+
+        for client_id in closed {
+            connections.remove(&client_id);
+            if let Some(client) = clients.remove(&client_id) {
+                client.shutdown();
+                println!("Client {} disconnected", client_id);
+            }
+        }
+        check_clients(&mut clients, &mut last_check);
+
+        if !pending {
+            continue;
+        }
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept client: {}", e);
+                continue;
+            }
+        };
+        match shm_ipc::peer_credentials(&stream) {
+            Ok(credentials) if credentials.uid == uid || Some(credentials.gid) == args.group => (),
+            Ok(credentials) => {
+                eprintln!(
+                    "Rejected process {} of user {}",
+                    credentials.pid, credentials.uid
+                );
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Failed to identify client: {}", e);
+                continue;
+            }
+        }
+        let Some(client_id) = (0..args.clients).find(|id| !connections.contains_key(id)) else {
+            eprintln!("Rejected client, all slots are in use");
+            continue;
+        };
+
+        match Queue::anonymous(config).and_then(|ipc| ipc.send_to(&stream).map(|_| ipc)) {
+            Ok(ipc) => {
+                let client = Client::start(client_id, ipc, table, args, shutting_down, &done_tx);
+                clients.insert(client_id, client);
+                connections.insert(client_id, stream);
+                println!("Client {} connected", client_id);
+            }
+            Err(e) => eprintln!(
+                "Failed to set up shared memory for client {}: {}",
+                client_id, e
+            ),
+        }
+    }
+
+    // No new clients can connect
+    if let Some(path) = &args.socket {
+        let _ = fs::remove_file(path);
+    }
+    clients
+}
+
+/// Sends a heartbeat to every client and stops serving clients that died or stopped sending theirs
+///
+/// Does nothing if the last check was less than `CONTROL_INTERVAL` ago.
+/// The queue of a dead client is torn down right away. A client that is still running keeps its queue mapped,
+/// so its queue is only closed, which makes it fail instead of waiting for us. The queue is removed once the client
+/// released its slot or connection.
+fn check_clients(clients: &mut HashMap<usize, Client>, last_check: &mut time::Instant) {
+    if last_check.elapsed() < CONTROL_INTERVAL {
+        return;
+    }
+    *last_check = time::Instant::now();
+
+    let mut dead = vec![];
+    for (client_id, client) in clients.iter_mut().filter(|(_, client)| !client.reset) {
+        client.ipc.heartbeat();
+        match client.ipc.check_peer(shm_ipc::HEARTBEAT_TIMEOUT) {
+            Ok(()) => (),
+            Err(e @ shm_ipc::Error::PeerDied(_)) => {
+                eprintln!("Removing queue of client {}: {}", client_id, e);
+                dead.push(*client_id);
+            }
+            Err(e) => {
+                eprintln!("Resetting queue of client {}: {}", client_id, e);
+                client.reset();
+            }
+        }
+    }
+    for client_id in dead {
+        if let Some(client) = clients.remove(&client_id) {
+            client.shutdown();
+        }
+    }
+}
+
+/// Listens on the Unix socket at `path`, which gets the mode and group of `config`
+///
+/// A socket left behind by a previous server is replaced, one that still accepts connections only with `force`
+fn bind(path: &Path, config: &shm_ipc::QueueConfig) -> io::Result<UnixListener> {
+    let listener = match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if !config.force && UnixStream::connect(path).is_ok() {
+                return Err(e);
+            }
+            fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        res => res,
+    }?;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(config.mode))?;
+    if let Some(group) = config.group {
+        std::os::unix::fs::chown(path, None, Some(group))?;
+    }
+    Ok(listener)
+}
+
+/// Parses permissions given in octal like `chmod`
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("{} is not an octal mode like 660", mode)),
+    }
+}
+
+/// Looks up the id of a group given by name or id
+fn parse_group(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group).map_err(|e| e.to_string())?;
+    // Only called while parsing the arguments, before any other thread could use getgrnam
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    match entry.is_null() {
+        true => Err(format!("unknown group {}", group)),
+        false => Ok(unsafe { (*entry).gr_gid }),
+    }
+}
+
+impl Client {
+    /// Starts the workers for the queue of client `client_id`
+    fn start(
+        client_id: usize,
+        ipc: Queue,
+        table: &Arc<hashtable::HashTable<TK, TV>>,
+        args: &Args,
+        shutting_down: &Arc<AtomicBool>,
+        done_tx: &mpsc::Sender<()>,
+    ) -> Self {
+        let ipc = Arc::new(ipc);
+        let workers = match args.ordered {
+            true => spawn_ordered(client_id, table, &ipc, args, shutting_down, done_tx),
+            false => (0..args.threads)
+                .map(|_| {
+                    spawn_worker(
+                        client_id,
+                        table.clone(),
+                        ipc.clone(),
+                        args.batch_size.max(1),
+                        shutting_down.clone(),
+                        done_tx.clone(),
+                    )
+                })
+                .collect(),
+        };
+        Self {
+            ipc,
+            workers,
+            reset: false,
+        }
+    }
+
+    /// Closes the queue and waits for the workers to exit, but keeps the queue itself
+    ///
+    /// The client might still use the locks in the queue, so they must not be destroyed yet.
+    fn reset(&mut self) {
+        if let Err(e) = self.ipc.close() {
+            eprintln!("Failed to close ipc: {}", e);
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        self.reset = true;
+    }
+
+    /// Closes the queue, waits for the workers to exit and removes the queue
+    fn shutdown(self) {
+        // Wakes up remaining workers and clients waiting for responses
+        if let Err(e) = self.ipc.stop().and_then(|_| self.ipc.close()) {
+            eprintln!("Failed to close ipc: {}", e);
+        }
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Starts a thread that answers requests of client `client_id` until its queue is closed
+fn spawn_worker(
+    client_id: usize,
+    table: Arc<hashtable::HashTable<TK, TV>>,
+    ipc_client: Arc<Queue>,
+    batch_size: usize,
+    shutting_down: Arc<AtomicBool>,
+    done_tx: mpsc::Sender<()>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        // Dropped when the worker exits
+        let _done = done_tx;
+        loop {
+            match ipc_client.request_get_many(batch_size) {
+                Ok(requests) => answer(client_id, &table, &ipc_client, &requests, &shutting_down),
+                Err(shm_ipc::Error::OwnerDied) => {
+                    eprintln!("Client {} died while holding the lock", client_id)
+                }
+                Err(shm_ipc::Error::Closed) => break,
+                Err(_) => (),
+            }
+        }
+    })
+}
+
+/// Starts a thread that takes the requests of client `client_id` in order and routes them to workers by key
+///
+/// Requests for the same key always go to the same worker, which executes them in the order they were sent.
+/// The workers exit once the dispatching thread is gone.
+fn spawn_ordered(
+    client_id: usize,
+    table: &Arc<hashtable::HashTable<TK, TV>>,
+    ipc_client: &Arc<Queue>,
+    args: &Args,
+    shutting_down: &Arc<AtomicBool>,
+    done_tx: &mpsc::Sender<()>,
+) -> Vec<thread::JoinHandle<()>> {
+    let threads = args.threads.max(1);
+    let (senders, mut workers): (Vec<_>, Vec<_>) = (0..threads)
+        .map(|_| {
+            // Bounded, so requests wait in the ring and the client notices if we fall behind
+            let (tx, rx) = mpsc::sync_channel::<Vec<Request>>(ipc_client.request_capacity());
+            let table = table.clone();
+            let ipc_client = ipc_client.clone();
+            let shutting_down = shutting_down.clone();
+            let done_tx = done_tx.clone();
+            let worker = thread::spawn(move || {
+                // Dropped when the worker exits
+                let _done = done_tx;
+                for requests in rx {
+                    answer(client_id, &table, &ipc_client, &requests, &shutting_down);
+                }
+            });
+            (tx, worker)
+        })
+        .collect();
+
+    let ipc_client = ipc_client.clone();
+    let batch_size = args.batch_size.max(1);
+    let done_tx = done_tx.clone();
+    workers.push(thread::spawn(move || {
+        let _done = done_tx;
+        loop {
+            match ipc_client.request_get_many(batch_size) {
+                Ok(requests) => {
+                    let routed = route_all(&ipc_client, requests, threads);
+                    for (sender, requests) in senders.iter().zip(routed) {
+                        if !requests.is_empty() && sender.send(requests).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(shm_ipc::Error::OwnerDied) => {
+                    eprintln!("Client {} died while holding the lock", client_id)
+                }
+                Err(shm_ipc::Error::Closed) => break,
+                Err(_) => (),
+            }
+        }
+    }));
+    workers
+}
+
+/// Splits `requests` into one batch per worker, see `route`
+///
+/// Requests for the same key end up in the same batch, in the order they were taken from the ring.
+fn route_all(ipc: &Queue, requests: Vec<Request>, workers: usize) -> Vec<Vec<Request>> {
+    let mut routed = vec![vec![]; workers];
+    for request in requests {
+        routed[route(ipc, &request, workers)].push(request);
+    }
+    routed
+}
+
+/// Picks the worker for `request` by the hash of its key
+///
+/// Requests with an invalid key all go to the first worker, which rejects them.
+fn route(ipc: &Queue, request: &Request, workers: usize) -> usize {
+    match ipc.blob_read(&request.key) {
+        Ok(key) => {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            (hasher.finish() % workers as u64) as usize
+        }
+        Err(_) => 0,
+    }
+}
+
+/// Executes `requests` in order and puts the responses into the ring of client `client_id`
+fn answer(
+    client_id: usize,
+    table: &hashtable::HashTable<TK, TV>,
+    ipc: &Queue,
+    requests: &[Request],
+    shutting_down: &AtomicBool,
+) {
+    let responses: Vec<_> = requests
+        .iter()
+        .map(|request| match shutting_down.load(Ordering::Relaxed) {
+            true => reject(ipc, request, Status::ShuttingDown),
+            false => {
+                println!("Got request: {:?}", request);
+                handle_request(table, ipc, request)
+            }
+        })
+        .collect();
+
+    let mut sent = 0;
+    while sent < responses.len() {
+        match ipc.response_put_many_blocking(&responses[sent..]) {
+            Ok(count) => sent += count,
+            Err(shm_ipc::Error::OwnerDied) => {
+                eprintln!("Client {} died while holding the lock", client_id)
+            }
+            Err(shm_ipc::Error::Closed) => break,
+            Err(_) => {
+                eprintln!("Something went wrong while trying to write to buffer");
+                break;
+            }
+        }
+    }
+}
+
+/// Copies `data` into the arena of `ipc`, waiting up to `ARENA_TIMEOUT` for space if necessary
+fn alloc_blob(ipc: &Queue, data: &[u8]) -> Result<Blob, shm_ipc::Error> {
+    loop {
+        // Space is freed once the client released earlier responses
+        match ipc.blob_alloc_timeout(data, ARENA_TIMEOUT) {
+            Err(shm_ipc::Error::OwnerDied) => (), // The arena was recovered, so just try again
+            res => return res,
+        }
+    }
+}
+
+/// Answers `request` with `status` without executing it
+///
+/// The key blob of the request is handed back to the client in the response, the value blob is released here.
+fn reject(ipc: &Queue, request: &Request, status: Status) -> shm_ipc::Response<Blob, Blob> {
+    let _ = ipc.blob_free(&request.val);
+    shm_ipc::Response {
+        operation: request.operation,
+        status: status.into(),
+        key: request.key,
+        val: Blob::default(),
+        counter: request.counter,
+    }
+}
+
+/// Executes `request` on the table and builds the response for the client
+///
+/// The key blob of the request is handed back to the client in the response, the value blob is released here.
+fn handle_request(
+    table: &hashtable::HashTable<TK, TV>,
+    ipc: &Queue,
+    request: &Request,
+) -> shm_ipc::Response<Blob, Blob> {
+    let mut response = shm_ipc::Response {
+        operation: request.operation,
+        status: Status::InvalidRequest.into(),
+        key: request.key,
+        val: Blob::default(),
+        counter: request.counter,
+    };
+
+    // Checked first, so nothing is taken from the arena for requests we cannot execute
+    let operation = match request.operation() {
+        Ok(operation) => operation,
+        Err(e) => {
+            eprintln!("Invalid operation in request: {}", e);
+            return reject(ipc, request, Status::Unsupported);
+        }
+    };
+    let key = match ipc.blob_read(&request.key) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("Invalid key in request: {}", e);
+            response.key = Blob::default();
+            return response;
+        }
+    };
+    let val = match ipc.blob_take(&request.val) {
+        Ok(val) => val,
+        Err(e) => {
+            eprintln!("Invalid value in request: {}", e);
+            return response;
+        }
+    };
+
+    let status = match operation {
+        Operation::Read => match table.read(&key) {
+            Some(value) => match alloc_blob(ipc, &value) {
+                Ok(blob) => {
+                    response.val = blob;
+                    Status::Ok
+                }
+                Err(shm_ipc::Error::ArenaFull) => Status::Busy,
+                Err(e) => {
+                    eprintln!("Failed to store value for response: {}", e);
+                    Status::Internal
+                }
+            },
+            None => Status::KeyMissing,
+        },
+        Operation::Insert => table
+            .add(key, val)
+            .map_or_else(Status::from, |_| Status::Ok),
+        Operation::Delete => table.delete(&key).map_or_else(Status::from, |_| Status::Ok),
+    };
+    response.status = status.into();
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_by_key() {
+        let ipc =
+            Queue::anonymous(&shm_ipc::QueueConfig::default()).expect("Failed to setup Queue");
+        let request = |operation, key: &[u8], counter| {
+            let key = ipc.blob_alloc(key).unwrap();
+            Request::new(operation, key, Blob::default(), counter)
+        };
+
+        for workers in 1..=8 {
+            // Every key is inserted and deleted again, each in a blob of its own
+            let mut requests = vec![];
+            for i in 0..16 {
+                let key = format!("key{i}");
+                requests.push(request(Operation::Insert, key.as_bytes(), 2 * i));
+                requests.push(request(Operation::Delete, key.as_bytes(), 2 * i + 1));
+            }
+
+            let routed = route_all(&ipc, requests, workers);
+            assert_eq!(routed.len(), workers);
+            for i in 0..16 {
+                let batch = routed
+                    .iter()
+                    .find(|batch| batch.iter().any(|request| request.counter == 2 * i))
+                    .expect("Request was lost");
+                let counters: Vec<_> = batch
+                    .iter()
+                    .map(|request| request.counter)
+                    .filter(|&counter| counter / 2 == i)
+                    .collect();
+                assert_eq!(counters, [2 * i, 2 * i + 1]);
+            }
+
+            for request in routed.iter().flatten() {
+                ipc.blob_free(&request.key).unwrap();
+            }
+        }
+    }
+}